@@ -0,0 +1,193 @@
+//! Engine benchmarks, run with `cargo bench --bench engines`. Every workload comes from a seeded rng
+//! so two runs write the same keys and values in the same order.
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::path::Path;
+use std::thread;
+use tempfile::TempDir;
+use kvs::{KvStore, KvsEngine, Options, Result, SledKvsEngine};
+
+const SEED: u64 = 0x6b76_7331;
+const KEYS: usize = 1000;
+
+type Open = fn(&Path) -> Result<Box<dyn KvsEngine>>;
+
+const ENGINES: [(&str, Open); 2] = [
+    ("kvs", |dir| Ok(Box::new(KvStore::open(dir)?))),
+    ("sled", |dir| Ok(Box::new(SledKvsEngine::open(dir)?))),
+];
+
+fn key(i: usize) -> Vec<u8> {
+    format!("key{:08}", i).into_bytes()
+}
+
+///`count` random values of `size` bytes
+fn values(rng: &mut StdRng, count: usize, size: usize) -> Vec<Vec<u8>> {
+    (0..count).map(|_| (0..size).map(|_| rng.gen()).collect()).collect()
+}
+
+///the key indices in a random order
+fn shuffled(rng: &mut StdRng) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..KEYS).collect();
+    order.shuffle(rng);
+    order
+}
+
+///an engine in a fresh directory holding `KEYS` keys, each written `rounds` times
+fn populated(open: Open, rounds: usize, size: usize) -> (TempDir, Box<dyn KvsEngine>) {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    let dir = TempDir::new().unwrap();
+    let mut engine = open(dir.path()).unwrap();
+    for _ in 0..rounds {
+        for (i, value) in values(&mut rng, KEYS, size).into_iter().enumerate() {
+            engine.set_bytes(key(i), value).unwrap();
+        }
+    }
+    (dir, engine)
+}
+
+fn writes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("write");
+    group.throughput(Throughput::Elements(KEYS as u64));
+    for (name, open) in ENGINES {
+        let mut rng = StdRng::seed_from_u64(SEED);
+        let values = values(&mut rng, KEYS, 100);
+        let orders = [("sequential", (0..KEYS).collect()), ("random", shuffled(&mut rng))];
+        for (pattern, order) in orders.iter() {
+            group.bench_function(BenchmarkId::new(*pattern, name), |b| {
+                b.iter_batched(
+                    || {
+                        let dir = TempDir::new().unwrap();
+                        let engine = open(dir.path()).unwrap();
+                        (dir, engine)
+                    },
+                    |(_dir, mut engine)| {
+                        for &i in order {
+                            engine.set_bytes(key(i), values[i].clone()).unwrap();
+                        }
+                    },
+                    BatchSize::PerIteration,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+fn reads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read");
+    group.throughput(Throughput::Elements(KEYS as u64));
+    for (name, open) in ENGINES {
+        let (_dir, mut engine) = populated(open, 1, 100);
+        let mut rng = StdRng::seed_from_u64(SEED);
+        let orders = [("sequential", (0..KEYS).collect()), ("random", shuffled(&mut rng))];
+        for (pattern, order) in orders.iter() {
+            group.bench_function(BenchmarkId::new(*pattern, name), |b| {
+                b.iter(|| {
+                    for &i in order {
+                        assert!(engine.get_bytes(key(i)).unwrap().is_some());
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+///100 writes of values from 16 bytes to 64 KiB
+fn value_sizes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("value_size");
+    for (name, open) in ENGINES {
+        for size in [16, 256, 4096, 65536] {
+            let mut rng = StdRng::seed_from_u64(SEED);
+            let values = values(&mut rng, 100, size);
+            group.throughput(Throughput::Bytes((100 * size) as u64));
+            group.bench_function(BenchmarkId::new(name, size), |b| {
+                b.iter_batched(
+                    || {
+                        let dir = TempDir::new().unwrap();
+                        let engine = open(dir.path()).unwrap();
+                        (dir, engine)
+                    },
+                    |(_dir, mut engine)| {
+                        for (i, value) in values.iter().enumerate() {
+                            engine.set_bytes(key(i), value.clone()).unwrap();
+                        }
+                    },
+                    BatchSize::PerIteration,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+///compacting a store where three quarters of the records are outdated
+fn compaction(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compaction");
+    group.sample_size(20);
+    for (name, open) in ENGINES {
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || populated(open, 4, 100),
+                |(_dir, mut engine)| engine.compact().unwrap(),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+///opening a directory written by a previous process, which replays the log for kvs
+fn recovery(c: &mut Criterion) {
+    let mut group = c.benchmark_group("open");
+    group.sample_size(20);
+    for (name, open) in ENGINES {
+        let (dir, engine) = populated(open, 4, 100);
+        drop(engine);
+        group.bench_function(name, |b| b.iter(|| open(dir.path()).unwrap()));
+    }
+    group.finish();
+}
+
+///random reads of kvs through the buffered reader and through the memory map, from the store and
+///from snapshots read by 4 threads at once
+fn read_path(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_path");
+    group.throughput(Throughput::Elements(KEYS as u64));
+    let (dir, engine) = populated(ENGINES[0].1, 1, 100);
+    drop(engine);
+    let order = shuffled(&mut StdRng::seed_from_u64(SEED));
+    for (name, mmap) in [("read_by_pos", false), ("mmap", true)] {
+        let mut store = KvStore::open_with(dir.path(), Options { mmap, ..Options::default() }).unwrap();
+        group.bench_function(BenchmarkId::new(name, 1), |b| {
+            b.iter(|| {
+                for &i in order.iter() {
+                    assert!(store.get_bytes(key(i)).unwrap().is_some());
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new(name, 4), |b| {
+            b.iter(|| {
+                let handles: Vec<_> = (0..4).map(|thread| {
+                    let mut snapshot = store.snapshot().unwrap();
+                    let order: Vec<usize> = order.iter().skip(thread).step_by(4).copied().collect();
+                    thread::spawn(move || {
+                        for i in order {
+                            assert!(snapshot.get_bytes(key(i)).unwrap().is_some());
+                        }
+                    })
+                }).collect();
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, writes, reads, value_sizes, compaction, recovery, read_path);
+criterion_main!(benches);
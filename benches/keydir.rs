@@ -0,0 +1,79 @@
+//! Memory taken by the index of `KvStore` per key, run with `cargo bench --bench keydir`.
+//! KEYDIR_KEYS sets the number of keys, 10 million by default, the data file of that many takes
+//! about 700 MB. The heap growth is measured by a counting allocator and printed next to the
+//! estimate of `Stats::index_bytes` and to the layout the index had before, which held every key twice.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tempfile::TempDir;
+use kvs::{CompactionPolicy, KvStore, KvsEngine, Options};
+
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+///the index entry before the keydir: the key again, `usize` offsets and an inline operand list
+#[allow(dead_code)]
+struct FormerIndex {
+    key: Vec<u8>,
+    start: usize,
+    end: usize,
+    operands: Vec<(usize, usize)>,
+    version: u64,
+}
+
+fn key(i: usize) -> Vec<u8> {
+    format!("key{:010}", i).into_bytes()
+}
+
+fn main() {
+    //`cargo bench -- --test` only checks that the benchmark runs
+    let keys = if std::env::args().any(|arg| arg == "--test") {
+        1000
+    } else {
+        std::env::var("KEYDIR_KEYS").ok().and_then(|keys| keys.parse().ok()).unwrap_or(10_000_000)
+    };
+    println!("{} keys of {} bytes", keys, key(0).len());
+
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let former: BTreeMap<Vec<u8>, FormerIndex> = (0..keys)
+        .map(|i| (key(i), FormerIndex { key: key(i), start: i * 50, end: i * 50 + 50, operands: vec![], version: i as u64 }))
+        .collect();
+    let measured = ALLOCATED.load(Ordering::Relaxed) - before;
+    println!("former index: {:.1} bytes per key", measured as f64 / keys as f64);
+    drop(former);
+
+    for (name, hashed_keys) in [("ordered keydir", false), ("hashed keydir", true)] {
+        let dir = TempDir::new().unwrap();
+        let compaction = CompactionPolicy { disabled: true, ..CompactionPolicy::default() };
+        let mut store = KvStore::open_with(dir.path(), Options { hashed_keys, compaction, ..Options::default() }).unwrap();
+        let before = ALLOCATED.load(Ordering::Relaxed);
+        for i in 0..keys {
+            store.set_bytes(key(i), b"value".to_vec()).unwrap();
+        }
+        let measured = ALLOCATED.load(Ordering::Relaxed) - before;
+        let estimated = store.stats().unwrap().index_bytes;
+        println!("{}: {:.1} bytes per key, {:.1} estimated", name, measured as f64 / keys as f64, estimated as f64 / keys as f64);
+    }
+}
@@ -0,0 +1,93 @@
+//! End-to-end benchmarks through a kvs-server child process, run with `cargo bench --bench server`.
+//! The server handles one connection at a time, the thread count is the number of concurrent clients.
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::net::SocketAddr;
+use std::process::{Child, Command as Process};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use kvs::utils::Command;
+use kvs::KvsClient;
+
+const SEED: u64 = 0x6b76_7331;
+const REQUESTS: usize = 400;
+
+///a kvs-server running in a temporary directory, killed on drop
+struct Server {
+    child: Child,
+    addr: SocketAddr,
+    _dir: TempDir,
+}
+
+impl Server {
+    fn start(engine: &str, port: u16) -> Server {
+        let dir = TempDir::new().unwrap();
+        let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+        let child = Process::new(env!("CARGO_BIN_EXE_kvs-server"))
+            .args(["--engine", engine, "--addr", &addr.to_string()])
+            .current_dir(&dir)
+            .spawn()
+            .unwrap();
+        let client = KvsClient::new(addr);
+        while !matches!(client.request(&Command::Ping), Ok(Command::Pong)) {
+            thread::sleep(Duration::from_millis(50));
+        }
+        Server { child, addr, _dir: dir }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+///the requests of every client, half of them sets of 100 byte values and half gets of the same keys
+fn workload(threads: usize) -> Vec<Vec<Command>> {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    (0..threads).map(|thread| {
+        (0..REQUESTS / threads).map(|i| {
+            let key = format!("key{}-{}", thread, rng.gen_range(0, 100)).into_bytes();
+            if i % 2 == 0 {
+                Command::Set(key, (0..100).map(|_| rng.gen()).collect())
+            } else {
+                Command::Get(key)
+            }
+        }).collect()
+    }).collect()
+}
+
+fn throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("server");
+    group.sample_size(20);
+    group.throughput(Throughput::Elements(REQUESTS as u64));
+    for (engine, port) in [("kvs", 4100), ("sled", 4101), ("memory", 4102)] {
+        let server = Server::start(engine, port);
+        for threads in [1, 2, 4, 8] {
+            let workload = Arc::new(workload(threads));
+            group.bench_function(BenchmarkId::new(engine, threads), |b| {
+                b.iter(|| {
+                    let handles: Vec<_> = (0..threads).map(|thread| {
+                        let (client, workload) = (KvsClient::new(server.addr), workload.clone());
+                        thread::spawn(move || {
+                            for request in &workload[thread] {
+                                assert!(!matches!(client.request(request).unwrap(), Command::Err(_)));
+                            }
+                        })
+                    }).collect();
+                    for handle in handles {
+                        handle.join().unwrap();
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
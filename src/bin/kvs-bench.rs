@@ -0,0 +1,297 @@
+use structopt::StructOpt;
+use kvs::{KvsClient, Result, Error};
+use kvs::utils::{parse_addr, Command};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "kvs-bench",
+about = "drive a running kvs-server with concurrent clients and report throughput and latency percentiles")]
+struct Opt {
+    #[structopt(long, default_value = "127.0.0.1:4000", parse(try_from_str = parse_addr))]
+    addr: SocketAddr,
+
+    ///clients sending requests at the same time
+    #[structopt(long, default_value = "4")]
+    threads: usize,
+
+    ///a YCSB core workload: a (50% updates), b (5% updates), c (read only), d (5% inserts, reads of the latest keys)
+    ///or f (50% read-modify-writes), setting the operation mix and the key distribution
+    #[structopt(long, parse(try_from_str = parse_workload), conflicts_with = "read-ratio")]
+    workload: Option<Workload>,
+
+    ///fraction of the requests that are reads, the others update an existing key
+    #[structopt(long, default_value = "0.5")]
+    read_ratio: f64,
+
+    ///uniform, zipfian or latest, defaults to the one of the workload or uniform
+    #[structopt(long, parse(try_from_str = parse_distribution))]
+    distribution: Option<Distribution>,
+
+    ///keys written before the run and picked by reads and updates
+    #[structopt(long, default_value = "1000")]
+    records: usize,
+
+    ///bytes of every value written
+    #[structopt(long, default_value = "100")]
+    value_size: usize,
+
+    ///seconds to run for
+    #[structopt(long, default_value = "10")]
+    duration: u64,
+
+    ///the keys are already loaded by a previous run
+    #[structopt(long)]
+    skip_load: bool,
+
+    ///seed of the rng of the first client, the next ones use the following seeds
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Distribution {
+    Uniform,
+    Zipfian,
+    ///zipfian over the keys inserted last
+    Latest,
+}
+
+fn parse_distribution(name: &str) -> std::result::Result<Distribution, String> {
+    match name {
+        "uniform" => Ok(Distribution::Uniform),
+        "zipfian" => Ok(Distribution::Zipfian),
+        "latest" => Ok(Distribution::Latest),
+        _ => Err(format!("unknown distribution {:?}, expected uniform, zipfian or latest", name)),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Read,
+    Update,
+    Insert,
+    ReadModifyWrite,
+}
+
+const OPS: [(Op, &str); 4] = [
+    (Op::Read, "read"),
+    (Op::Update, "update"),
+    (Op::Insert, "insert"),
+    (Op::ReadModifyWrite, "read-modify-write"),
+];
+
+///the share of every `Op` in the requests, in the order of `OPS`, and the key distribution
+#[derive(Debug, Clone, Copy)]
+struct Workload {
+    mix: [f64; 4],
+    distribution: Distribution,
+}
+
+fn parse_workload(name: &str) -> std::result::Result<Workload, String> {
+    let (mix, distribution) = match name {
+        "a" => ([0.5, 0.5, 0.0, 0.0], Distribution::Zipfian),
+        "b" => ([0.95, 0.05, 0.0, 0.0], Distribution::Zipfian),
+        "c" => ([1.0, 0.0, 0.0, 0.0], Distribution::Zipfian),
+        "d" => ([0.95, 0.0, 0.05, 0.0], Distribution::Latest),
+        "e" => return Err("workload e is made of range scans, which kvs-server does not offer".to_owned()),
+        "f" => ([0.5, 0.0, 0.0, 0.5], Distribution::Zipfian),
+        _ => return Err(format!("unknown workload {:?}, expected a, b, c, d or f", name)),
+    };
+    Ok(Workload { mix, distribution })
+}
+
+///the zipfian generator of YCSB (Gray et al., Quickly Generating Billion-Record Synthetic Databases),
+///item 0 is the most popular
+#[derive(Clone)]
+struct Zipfian {
+    items: usize,
+    theta: f64,
+    zetan: f64,
+    alpha: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    const THETA: f64 = 0.99;
+
+    fn new(items: usize) -> Zipfian {
+        let theta = Self::THETA;
+        let zeta = |n: usize| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zetan = zeta(items);
+        let eta = (1.0 - (2.0 / items as f64).powf(1.0 - theta)) / (1.0 - zeta(2) / zetan);
+        Zipfian { items, theta, zetan, alpha: 1.0 / (1.0 - theta), eta }
+    }
+
+    fn next(&self, rng: &mut StdRng) -> usize {
+        let u: f64 = rng.gen();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1;
+        }
+        let item = (self.items as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as usize;
+        item.min(self.items - 1)
+    }
+}
+
+///picks the keys of one client, `inserted` is shared by all of them
+struct Keys {
+    distribution: Distribution,
+    zipfian: Zipfian,
+    inserted: Arc<AtomicUsize>,
+}
+
+impl Keys {
+    fn next(&self, rng: &mut StdRng) -> usize {
+        let inserted = self.inserted.load(Ordering::SeqCst);
+        match self.distribution {
+            Distribution::Uniform => rng.gen_range(0, inserted),
+            Distribution::Zipfian => self.zipfian.next(rng),
+            Distribution::Latest => inserted - 1 - self.zipfian.next(rng).min(inserted - 1),
+        }
+    }
+
+    fn insert(&self) -> usize {
+        self.inserted.fetch_add(1, Ordering::SeqCst)
+    }
+}
+
+fn key(i: usize) -> Vec<u8> {
+    format!("user{:010}", i).into_bytes()
+}
+
+fn value(rng: &mut StdRng, size: usize) -> Vec<u8> {
+    (0..size).map(|_| rng.gen_range(b'a', b'z' + 1)).collect()
+}
+
+///latencies in microseconds per `Op` and the number of requests answered with an error
+#[derive(Default)]
+struct Samples {
+    latencies: [Vec<u64>; 4],
+    errors: [u64; 4],
+}
+
+fn main() -> Result<()> {
+    let opt = Opt::from_args();
+    if opt.records == 0 || opt.threads == 0 {
+        eprintln!("--records and --threads must be positive");
+        std::process::exit(1);
+    }
+    let workload = opt.workload.unwrap_or(Workload {
+        mix: [opt.read_ratio, 1.0 - opt.read_ratio, 0.0, 0.0],
+        distribution: Distribution::Uniform,
+    });
+    let distribution = opt.distribution.unwrap_or(workload.distribution);
+    let client = KvsClient::new(opt.addr);
+    client.request(&Command::Ping)?;
+
+    if !opt.skip_load {
+        let started = Instant::now();
+        let handles: Vec<_> = (0..opt.threads).map(|thread| {
+            let (client, seed, records, threads, size) = (KvsClient::new(opt.addr), opt.seed, opt.records, opt.threads, opt.value_size);
+            thread::spawn(move || -> Result<()> {
+                let mut rng = StdRng::seed_from_u64(seed + thread as u64);
+                for i in (thread..records).step_by(threads) {
+                    expect_ok(client.request(&Command::Set(key(i), value(&mut rng, size)))?)?;
+                }
+                Ok(())
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        println!("loaded {} records in {:.2}s", opt.records, started.elapsed().as_secs_f64());
+    }
+
+    let inserted = Arc::new(AtomicUsize::new(opt.records));
+    let deadline = Instant::now() + Duration::from_secs(opt.duration);
+    let zipfian = Zipfian::new(opt.records);
+    let started = Instant::now();
+    let handles: Vec<_> = (0..opt.threads).map(|thread| {
+        let client = KvsClient::new(opt.addr);
+        let keys = Keys { distribution, zipfian: zipfian.clone(), inserted: inserted.clone() };
+        let (seed, size) = (opt.seed + thread as u64, opt.value_size);
+        thread::spawn(move || run(client, keys, workload.mix, size, seed, deadline))
+    }).collect();
+    let mut samples = Samples::default();
+    for handle in handles {
+        let thread = handle.join().unwrap();
+        for (i, latencies) in thread.latencies.iter().enumerate() {
+            samples.latencies[i].extend(latencies);
+            samples.errors[i] += thread.errors[i];
+        }
+    }
+    report(samples, started.elapsed());
+    Ok(())
+}
+
+///send requests until `deadline`, the kind of each one drawn from `mix`
+fn run(client: KvsClient, keys: Keys, mix: [f64; 4], size: usize, seed: u64, deadline: Instant) -> Samples {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut samples = Samples::default();
+    while Instant::now() < deadline {
+        let mut draw: f64 = rng.gen();
+        let op = mix.iter().position(|share| {
+            draw -= share;
+            draw < 0.0
+        }).unwrap_or(0);
+        let started = Instant::now();
+        let res = match OPS[op].0 {
+            Op::Read => client.request(&Command::Get(key(keys.next(&mut rng)))).and_then(expect_ok),
+            Op::Update => client.request(&Command::Set(key(keys.next(&mut rng)), value(&mut rng, size))).and_then(expect_ok),
+            Op::Insert => client.request(&Command::Set(key(keys.insert()), value(&mut rng, size))).and_then(expect_ok),
+            Op::ReadModifyWrite => {
+                let key = key(keys.next(&mut rng));
+                client.request(&Command::Get(key.clone())).and_then(expect_ok)
+                    .and_then(|_| client.request(&Command::Set(key, value(&mut rng, size))))
+                    .and_then(expect_ok)
+            }
+        };
+        samples.latencies[op].push(started.elapsed().as_micros() as u64);
+        if res.is_err() {
+            samples.errors[op] += 1;
+        }
+    }
+    samples
+}
+
+fn expect_ok(response: Command) -> Result<()> {
+    match response {
+        Command::Ok(_) => Ok(()),
+        Command::Err(msg) => Err(Error::ServerError(msg)),
+        _ => Err(Error::InternalError),
+    }
+}
+
+///one line per kind of request and one for all of them
+fn report(mut samples: Samples, elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+    let mut all = Vec::new();
+    for (i, (_, name)) in OPS.iter().enumerate() {
+        if samples.latencies[i].is_empty() {
+            continue;
+        }
+        all.extend(&samples.latencies[i]);
+        print_line(name, &mut samples.latencies[i], samples.errors[i], seconds);
+    }
+    print_line("total", &mut all, samples.errors.iter().sum(), seconds);
+}
+
+fn print_line(name: &str, latencies: &mut [u64], errors: u64, seconds: f64) {
+    latencies.sort_unstable();
+    let percentile = |p: f64| {
+        let rank = (latencies.len() as f64 * p).ceil() as usize;
+        latencies.get(rank.saturating_sub(1)).copied().unwrap_or(0)
+    };
+    println!("{}: {} ops, {:.1} ops/s, {} errors, p50 {}us, p99 {}us, p999 {}us, max {}us",
+             name, latencies.len(), latencies.len() as f64 / seconds, errors,
+             percentile(0.5), percentile(0.99), percentile(0.999), latencies.last().copied().unwrap_or(0));
+}
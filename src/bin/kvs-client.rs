@@ -1,10 +1,10 @@
 use structopt::{StructOpt};
-use Kvs::{Result};
+use structopt::clap::AppSettings;
+use kvs::{Result, Error, KvsClient, At, Event};
 use std::process::exit;
-use std::net::{TcpStream, SocketAddr};
-use Kvs::utils::{parse_addr, Command};
-use std::io::{Write, Read};
-use serde_json::error::Category::Eof;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use kvs::utils::{parse_addr, Command};
 
 #[derive(Debug,StructOpt)]
 #[structopt(name = "kvs-client",
@@ -13,6 +13,14 @@ struct Opt {
 
     #[structopt(subcommand)]
     sub_opt: SubOpt,
+
+    ///how keys and values given on the command line are encoded: utf8, hex or base64
+    #[structopt(long, global = true, default_value = "utf8")]
+    input_format: Format,
+
+    ///how printed values are encoded: utf8, hex or base64
+    #[structopt(long, global = true, default_value = "utf8")]
+    output_format: Format,
 }
 
 #[derive(Debug,StructOpt)]
@@ -36,39 +44,258 @@ pub enum SubOpt {
         #[structopt(long, default_value = "127.0.0.1:4000", parse(try_from_str = parse_addr))]
         addr: SocketAddr,
     },
+    #[structopt(name = "cas", about = "replace the value only if the stored one equals --expected, absent options mean an absent key")]
+    Cas {
+        key: String,
+        #[structopt(long)]
+        expected: Option<String>,
+        #[structopt(long)]
+        new: Option<String>,
+        #[structopt(long, default_value = "127.0.0.1:4000", parse(try_from_str = parse_addr))]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "setnx", about = "store key-value mapping only if the key is absent")]
+    SetIfAbsent {
+        key: String,
+        value: String,
+        #[structopt(long, default_value = "127.0.0.1:4000", parse(try_from_str = parse_addr))]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "incr", about = "add delta (default 1) to an integer value and print the result",
+    setting = AppSettings::AllowNegativeNumbers)]
+    Incr {
+        key: String,
+        #[structopt(default_value = "1")]
+        delta: i64,
+        #[structopt(long, default_value = "127.0.0.1:4000", parse(try_from_str = parse_addr))]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "get-at", about = "get the value a key had at a sequence number or a time")]
+    GetAt {
+        key: String,
+        #[structopt(long, required_unless = "timestamp", conflicts_with = "timestamp")]
+        sequence: Option<u64>,
+        ///milliseconds since the unix epoch
+        #[structopt(long)]
+        timestamp: Option<u64>,
+        #[structopt(long, default_value = "127.0.0.1:4000", parse(try_from_str = parse_addr))]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "watch", about = "print the writes to keys starting with --prefix as they happen")]
+    Watch {
+        #[structopt(long, default_value = "")]
+        prefix: String,
+        #[structopt(long, default_value = "127.0.0.1:4000", parse(try_from_str = parse_addr))]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "changes", about = "print every mutation after --since and then the new ones as they happen")]
+    Changes {
+        #[structopt(long, default_value = "0")]
+        since: u64,
+        #[structopt(long, default_value = "127.0.0.1:4000", parse(try_from_str = parse_addr))]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "backup", about = "make the server write a consistent backup into an empty directory on its machine")]
+    Backup {
+        dest: String,
+        #[structopt(long, default_value = "127.0.0.1:4000", parse(try_from_str = parse_addr))]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "stats", about = "print the statistics of the engine")]
+    Stats {
+        #[structopt(long, default_value = "127.0.0.1:4000", parse(try_from_str = parse_addr))]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "compact", about = "make the server compact its engine now")]
+    Compact {
+        #[structopt(long, default_value = "127.0.0.1:4000", parse(try_from_str = parse_addr))]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "history", about = "list the retained versions of a key, oldest first")]
+    History {
+        key: String,
+        #[structopt(long, default_value = "127.0.0.1:4000", parse(try_from_str = parse_addr))]
+        addr: SocketAddr,
+    },
+}
+
+///textual encoding of binary keys and values on the command line
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Utf8,
+    Hex,
+    Base64,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "utf8" => Ok(Format::Utf8),
+            "hex" => Ok(Format::Hex),
+            "base64" => Ok(Format::Base64),
+            _ => Err(format!("unknown format {}, expected utf8, hex or base64", s)),
+        }
+    }
+}
+
+impl Format {
+    fn decode(self, input: String) -> Result<Vec<u8>> {
+        match self {
+            Format::Utf8 => Ok(input.into_bytes()),
+            Format::Hex => hex::decode(input).map_err(|_| Error::InvalidEncodingError),
+            Format::Base64 => base64::decode(input).map_err(|_| Error::InvalidEncodingError),
+        }
+    }
+
+    fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            Format::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            Format::Hex => hex::encode(bytes),
+            Format::Base64 => base64::encode(bytes),
+        }
+    }
 }
 
 fn main() ->Result<()>{
     let opt = Opt::from_args();
+    let input = opt.input_format;
+    let output = opt.output_format;
+    let decode = |input_str: String| input.decode(input_str).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(1);
+    });
     let (cmd,addr)=match opt.sub_opt{
         SubOpt::Set{ key,value,addr }=>{
-            (Command::Set(key,value),addr)
+            (Command::Set(decode(key),decode(value)),addr)
 
         },
         SubOpt::Get{key,addr}=>{
-            (Command::Get(key),addr)
+            (Command::Get(decode(key)),addr)
         },
         SubOpt::Remove {key,addr}=>{
-            (Command::Remove(key),addr)
+            (Command::Remove(decode(key)),addr)
+        },
+        SubOpt::Cas {key,expected,new,addr}=>{
+            (Command::Cas(decode(key),expected.map(decode),new.map(decode)),addr)
+        },
+        SubOpt::SetIfAbsent {key,value,addr}=>{
+            (Command::SetIfAbsent(decode(key),decode(value)),addr)
+        },
+        SubOpt::Incr {key,delta,addr}=>{
+            (Command::Incr(decode(key),delta),addr)
+        },
+        SubOpt::GetAt {key,sequence,timestamp,addr}=>{
+            let at = match sequence {
+                Some(sequence) => At::Sequence(sequence),
+                None => At::Timestamp(timestamp.unwrap_or_default()),
+            };
+            (Command::GetAt(decode(key),at),addr)
+        },
+        SubOpt::History {key,addr}=>{
+            (Command::History(decode(key)),addr)
+        },
+        SubOpt::Backup {dest,addr}=>{
+            (Command::Backup(dest),addr)
+        },
+        SubOpt::Stats {addr}=>{
+            (Command::Stats,addr)
+        },
+        SubOpt::Compact {addr}=>{
+            (Command::Compact,addr)
+        },
+        SubOpt::Watch {prefix,addr}=>{
+            for event in KvsClient::new(addr).watch(decode(prefix))? {
+                print_event(event?, output);
+            }
+            return Ok(());
+        },
+        SubOpt::Changes {since,addr}=>{
+            for event in KvsClient::new(addr).changes(since)? {
+                print_event(event?, output);
+            }
+            return Ok(());
         }
     };
-    let mut stream =  TcpStream::connect(addr).expect("connect error");
-    serde_json::ser::to_writer(stream.try_clone()?,&cmd)?;
-    let cmd:Command = serde_json::from_reader(stream)?;
+    let is_get = matches!(cmd, Command::Get(_) | Command::GetAt(..));
+    let is_incr = matches!(cmd, Command::Incr(..));
+    let is_remove = matches!(cmd, Command::Remove(_));
+    let cmd = KvsClient::new(addr).request(&cmd)?;
     match cmd {
         Command::Pong => {
             println!("Pong!");
         }
         Command::Ok(res) => {
-            if let Some(str)=res{
-                println!("{}",str);
-            }else { println!("Ok") }
+            if let Some(bytes)=res{
+                //the new counter is a number, not a stored value
+                if is_incr {
+                    println!("{}", String::from_utf8_lossy(&bytes));
+                } else {
+                    println!("{}", output.encode(&bytes));
+                }
+            }else if is_get {
+                println!("Key not found");
+            }
+        }
+        Command::Conflict(current) => {
+            match current {
+                Some(bytes) => eprintln!("Conflict, current value: {}", output.encode(&bytes)),
+                None => eprintln!("Conflict, key not found"),
+            }
+            exit(1);
+        }
+        Command::EngineStats(stats) => {
+            println!("keys: {}", stats.keys);
+            println!("live bytes: {}", stats.live_bytes);
+            println!("dead bytes: {}", stats.dead_bytes);
+            println!("segments: {}", stats.segments);
+            println!("compactions: {}", stats.compactions);
+            match stats.last_compaction {
+                Some(duration) => println!("last compaction: {} ms", duration.as_millis()),
+                None => println!("last compaction: never"),
+            }
+            println!("reads: {}", stats.reads);
+            println!("writes: {}", stats.writes);
+            println!("cache hits: {}", stats.cache_hits);
+            println!("cache misses: {}", stats.cache_misses);
+            println!("index bytes: {}", stats.index_bytes);
+            if let Some(duration) = stats.recovery {
+                println!("recovery: {} records in {} ms", stats.recovered_records, duration.as_millis());
+            }
         }
-        Command::Err => {
-            println!("Err!");
+        Command::Versions(versions) => {
+            //one version per line: sequence, timestamp and value
+            for version in versions {
+                match version.value {
+                    Some(bytes) => println!("{}\t{}\t{}", version.sequence, version.timestamp, output.encode(&bytes)),
+                    None => println!("{}\t{}\t(removed)", version.sequence, version.timestamp),
+                }
+            }
+        }
+        Command::Err(msg) => {
+            //a missing key is reported the way `get` reports it
+            if is_remove && msg == Error::KeyNotFoundError.to_string() {
+                eprintln!("Key not found");
+            } else {
+                eprintln!("{}", msg);
+            }
+            exit(1);
         }
         _ => {}
     }
 
     Ok(())
 }
+
+///one line per event: sequence, kind, key and the new value or merge operand
+fn print_event(event: Event, output: Format) {
+    match event {
+        Event::Set { sequence, key, value } =>
+            println!("{}\tset\t{}\t{}", sequence, output.encode(&key), output.encode(&value)),
+        Event::Remove { sequence, key } =>
+            println!("{}\tremove\t{}", sequence, output.encode(&key)),
+        Event::Merge { sequence, key, operand } =>
+            println!("{}\tmerge\t{}\t{}", sequence, output.encode(&key), output.encode(&operand)),
+    }
+}
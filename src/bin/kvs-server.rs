@@ -1,11 +1,12 @@
 use structopt::{StructOpt};
-use Kvs::{KvStore, Result, Error, KvsEngine,SledKvsEngine};
-use std::process::exit;
+use kvs::{KvStore, Result, Error, KvsEngine,SledKvsEngine, MemoryKvsEngine, Options, Retention, Changes, CompactionPolicy};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use kvs::metrics::{Metrics, serve_metrics};
 use std::path::PathBuf;
-use std::net::{ToSocketAddrs, SocketAddr, AddrParseError, TcpListener};
-use std::str::FromStr;
-use std::io::{Read, Write};
-use Kvs::utils::{parse_addr, Command};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use kvs::utils::{parse_addr, Command};
 use serde::Deserialize;
 
 #[derive(Debug, StructOpt)]
@@ -15,71 +16,119 @@ struct Opt {
     #[structopt(long, default_value = "127.0.0.1:4000", parse(try_from_str = parse_addr))]
     addr: SocketAddr,
 
+    ///kvs, sled or memory, which keeps nothing on disk
     #[structopt(long)]
     engine: Option<String>,
+
+    ///keep this many prior versions of every key (kvs and memory engines)
+    #[structopt(long, conflicts_with = "retain-age")]
+    retain_versions: Option<usize>,
+
+    ///keep the prior versions that were current within this many seconds (kvs and memory engines)
+    #[structopt(long)]
+    retain_age: Option<u64>,
+
+    ///keep this many logs replaced by compaction so change feeds can resume from older sequence numbers (kvs engine only)
+    #[structopt(long, default_value = "0")]
+    retained_segments: usize,
+
+    ///compact once this many bytes of records are outdated (kvs and memory engines)
+    #[structopt(long, default_value = "2097152")]
+    compaction_dead_bytes: usize,
+
+    ///and only once the outdated bytes reach this fraction of the live ones (kvs and memory engines)
+    #[structopt(long, default_value = "0")]
+    compaction_dead_ratio: f64,
+
+    ///only compact automatically between these hours of the day in UTC, as START-END (kvs and memory engines)
+    #[structopt(long, parse(try_from_str = parse_window))]
+    compaction_window: Option<(u32, u32)>,
+
+    ///never compact automatically, `kvs-client compact` still does (kvs and memory engines)
+    #[structopt(long)]
+    no_auto_compaction: bool,
+
+    ///read records through a memory map of the data file (kvs engine only)
+    #[structopt(long)]
+    mmap: bool,
+
+    ///keep up to this many bytes of recently read values in memory (kvs engine only)
+    #[structopt(long, default_value = "0")]
+    cache_bytes: usize,
+
+    ///keep only a hash of every key in memory, for key sets too large for it (kvs engine only)
+    #[structopt(long)]
+    hashed_keys: bool,
+
+    ///serve Prometheus metrics over HTTP on this address
+    #[structopt(long, parse(try_from_str = parse_addr))]
+    metrics_addr: Option<SocketAddr>,
 }
 
 fn main() -> Result<()> {
     let opt = Opt::from_args();
-    let listener = TcpListener::bind(opt.addr)?;
     let kvs_exist =  PathBuf::new().join("./.data").is_file() ;
     let sled_exist = PathBuf::new().join("./db").is_file() ;
-    let engine:Result<Box<dyn KvsEngine>>= match opt.engine {
-        Some(engine)=>{
-             if engine=="kvs".to_owned()&&!sled_exist{
-                 Ok(Box::new(KvStore::open(".")?))
-            }else if engine=="sled".to_owned()&&!kvs_exist {
-                Ok(Box::new(SledKvsEngine::open(".")?))
-            }else {
-                Err(Error::InvalidEngineError)
-            }
-        },
-        None=>{
-            if sled_exist {
-                 Ok(Box::new(SledKvsEngine::open(".")?))
-            }else {
-                Ok(Box::new(KvStore::open(".")?))
+    let engine_name = match opt.engine {
+        Some(engine) => {
+            if (engine == "kvs" && sled_exist) || (engine == "sled" && kvs_exist) || (engine != "kvs" && engine != "sled" && engine != "memory") {
+                return Err(Error::InvalidEngineError);
             }
-
+            engine
         }
+        None => if sled_exist { "sled".to_owned() } else { "kvs".to_owned() }
     };
-    let mut engine = engine.unwrap();
+    eprintln!("kvs-server {}", env!("CARGO_PKG_VERSION"));
+    eprintln!("engine: {}, listening on {}", engine_name, opt.addr);
+
+    let retention = match (opt.retain_versions, opt.retain_age) {
+        (Some(count), _) => Some(Retention::Versions(count)),
+        (_, Some(secs)) => Some(Retention::Age(Duration::from_secs(secs))),
+        _ => None,
+    };
+    let compaction = CompactionPolicy {
+        dead_bytes: opt.compaction_dead_bytes,
+        dead_ratio: opt.compaction_dead_ratio,
+        window: opt.compaction_window,
+        disabled: opt.no_auto_compaction,
+    };
+    let mut engine: Box<dyn KvsEngine + Send> = if engine_name == "sled" {
+        Box::new(SledKvsEngine::open(".")?)
+    } else if engine_name == "memory" {
+        Box::new(MemoryKvsEngine::with_options(Options { retention, compaction, ..Options::default() }))
+    } else {
+        Box::new(KvStore::open_with(".", Options { retention, retained_segments: opt.retained_segments, compaction, mmap: opt.mmap, cache_bytes: opt.cache_bytes, hashed_keys: opt.hashed_keys, recovery_progress: Some(Arc::new(report_recovery)), ..Options::default() })?)
+    };
+    let stats = engine.stats()?;
+    if let Some(duration) = stats.recovery {
+        eprintln!("recovered {} records in {} ms", stats.recovered_records, duration.as_millis());
+    }
+    //the metrics listener reads the engine statistics between two requests
+    let engine: Arc<Mutex<Box<dyn KvsEngine + Send>>> = Arc::new(Mutex::new(engine));
+    let metrics = Arc::new(Metrics::default());
+    if let Some(metrics_addr) = opt.metrics_addr {
+        let listener = TcpListener::bind(metrics_addr)?;
+        eprintln!("metrics on http://{}/metrics", metrics_addr);
+        let (engine, metrics) = (engine.clone(), metrics.clone());
+        thread::spawn(move || serve_metrics(listener, || {
+            let stats = engine.lock().unwrap().stats().ok();
+            metrics.render(stats.as_ref())
+        }));
+    }
+    let listener = TcpListener::bind(opt.addr)?;
 
     for stream in listener.incoming() {
         match stream {
-            Ok(mut stream) => {
-
-                let mut de = serde_json::Deserializer::from_reader(stream.try_clone()?);
-                let cmd :Command=Command::deserialize(&mut de) ?;
-
-                match cmd {
-                    Command::Set(k, v) => {
-                        println!("0");
-                        engine.set(k, v)?;
-                        serde_json::to_writer(stream.try_clone()?,&Command::Ok(None));
-                        println!("1");
-                    }
-                    Command::Get(k) => {
-                        println!("2");
-                        let v = engine.get(k)?;
-                        match v {
-                            None => {  serde_json::to_writer(stream.try_clone()?,&Command::Ok(None));}
-                            Some(str) => {  serde_json::to_writer(stream.try_clone()?,&Command::Ok(Some(str))); }
-                        };
-                        println!("3");
+            Ok(stream) => {
+                metrics.connection_opened();
+                //every connection is read by a thread of its own, a client that sends nothing holds up no other
+                let (engine, metrics) = (engine.clone(), metrics.clone());
+                thread::spawn(move || {
+                    if let Err(e) = serve(&engine, stream, &metrics) {
+                        eprintln!("error on serving connection: {}", e);
                     }
-                    Command::Remove(k) => {
-                        if let Ok(()) = engine.remove(k) {
-                            serde_json::to_writer(stream.try_clone()?,&Command::Ok(None));
-                        } else {  serde_json::to_writer(stream.try_clone()?,&Command::Err);};
-
-                    },
-                    Command::Ping=>{
-                        serde_json::to_writer(stream.try_clone()?,&"Pong")?;
-                    }
-                    _ => {}
-                }
-
+                    metrics.connection_closed();
+                });
             }
             Err(_) => {
                 return Err(Error::ConnectFailedError);
@@ -89,3 +138,93 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+///print how far the replay of the log is, once every 64 MiB, and the torn record cut off at its end
+fn report_recovery(replayed: u64, total: u64, torn: u64) {
+    if torn > 0 {
+        eprintln!("cut off a torn record of {} bytes at the end of the log", torn);
+    } else if replayed < total {
+        eprintln!("replaying the log: {} of {} MiB", replayed >> 20, total >> 20);
+    }
+}
+
+///handle one connection, the engine is only locked once its command has been read
+fn serve(engine: &Mutex<Box<dyn KvsEngine + Send>>, stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    let mut de = serde_json::Deserializer::from_reader(stream.try_clone()?);
+    let cmd: Command = Command::deserialize(&mut de)?;
+    let name = cmd.name();
+    let started = Instant::now();
+    let events = match cmd {
+        Command::Watch(prefix) => engine.lock().unwrap().watch(prefix).map(|watch| -> Changes { Box::new(watch.map(Ok)) }),
+        Command::Changes(since) => engine.lock().unwrap().changes(since),
+        cmd => {
+            let response = handle(engine.lock().unwrap().as_mut(), cmd);
+            metrics.record(name, started.elapsed(), matches!(response, Command::Err(_)));
+            serde_json::to_writer(stream, &response)?;
+            return Ok(());
+        }
+    };
+    metrics.record(name, started.elapsed(), events.is_err());
+    match events {
+        Ok(events) => stream_events(events, stream),
+        Err(e) => serde_json::to_writer(stream, &Command::Err(e.to_string()))?,
+    }
+    Ok(())
+}
+
+///write every event until the client hangs up
+fn stream_events(events: Changes, stream: TcpStream) {
+    for event in events {
+        let response = match event {
+            Ok(event) => Command::Event(event),
+            Err(e) => Command::Err(e.to_string()),
+        };
+        let failed = matches!(response, Command::Err(_));
+        if serde_json::to_writer(&stream, &response).is_err() || failed {
+            break;
+        }
+    }
+}
+
+///hours as START-END, each below 24
+fn parse_window(window: &str) -> std::result::Result<(u32, u32), String> {
+    let invalid = || format!("invalid window {:?}, expected START-END hours such as 22-4", window);
+    let (start, end) = window.split_once('-').ok_or_else(invalid)?;
+    let start: u32 = start.parse().map_err(|_| invalid())?;
+    let end: u32 = end.parse().map_err(|_| invalid())?;
+    if start >= 24 || end >= 24 {
+        return Err(invalid());
+    }
+    Ok((start, end))
+}
+
+fn handle(engine: &mut dyn KvsEngine, cmd: Command) -> Command {
+    let res = match cmd {
+        Command::Set(k, v) => engine.set_bytes(k, v).map(|_| Command::Ok(None)),
+        Command::Get(k) => engine.get_bytes(k).map(Command::Ok),
+        Command::Remove(k) => engine.remove_bytes(k).map(|_| Command::Ok(None)),
+        Command::Cas(k, expected, new) => engine.compare_and_swap_bytes(k, expected, new).map(conditional_response),
+        Command::SetIfAbsent(k, v) => engine.compare_and_swap_bytes(k, None, Some(v)).map(conditional_response),
+        Command::Incr(k, delta) => engine.increment_bytes(k, delta).map(|v| Command::Ok(Some(v.to_string().into_bytes()))),
+        Command::TxnGet(k) => engine.get_versioned(k).map(|(v, version)| Command::Versioned(v, version)),
+        Command::TxnCommit(txn) => match engine.commit(txn) {
+            Err(Error::TransactionConflictError) => Ok(Command::TxnConflict),
+            res => res.map(|_| Command::Ok(None)),
+        },
+        Command::GetAt(k, at) => engine.get_at_bytes(k, at).map(Command::Ok),
+        Command::History(k) => engine.history_bytes(k).map(Command::Versions),
+        Command::Backup(dest) => engine.backup(dest.as_ref()).map(|_| Command::Ok(None)),
+        Command::Stats => engine.stats().map(Command::EngineStats),
+        Command::Compact => engine.compact().map(|_| Command::Ok(None)),
+        Command::Ping => Ok(Command::Pong),
+        _ => Ok(Command::Err("unexpected command".to_owned())),
+    };
+    res.unwrap_or_else(|e| Command::Err(e.to_string()))
+}
+
+fn conditional_response(res: kvs::CompareAndSwapResult<Vec<u8>>) -> Command {
+    match res {
+        Ok(()) => Command::Ok(None),
+        Err(conflict) => Command::Conflict(conflict.current),
+    }
+}
@@ -0,0 +1,167 @@
+use structopt::StructOpt;
+use kvs::{restore, export, import, migrate, ExportFormat, KvStore, SledKvsEngine, KvsEngine, Result, Error};
+use kvs::inspect::{self, Entry, RecordKind};
+use std::process::exit;
+use std::fs::{self, File};
+use std::io::{stdin, stdout};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "kvs-tool",
+about = "offline maintenance of a kvs data directory, the server must not be running on it")]
+enum Opt {
+    #[structopt(name = "restore", about = "copy a backup made by `kvs-client backup` into an empty data directory")]
+    Restore {
+        backup: PathBuf,
+        #[structopt(long, default_value = ".")]
+        dir: PathBuf,
+    },
+    #[structopt(name = "export", about = "write every live key-value pair as json lines or the binary format")]
+    Export {
+        #[structopt(long, default_value = ".")]
+        dir: PathBuf,
+        ///kvs or sled, detected from the data files when absent
+        #[structopt(long)]
+        engine: Option<String>,
+        ///json or binary
+        #[structopt(long, default_value = "json")]
+        format: ExportFormat,
+        ///file to write, standard output when absent
+        #[structopt(long)]
+        output: Option<PathBuf>,
+    },
+    #[structopt(name = "import", about = "set every key-value pair of an export into a data directory")]
+    Import {
+        #[structopt(long, default_value = ".")]
+        dir: PathBuf,
+        ///kvs or sled, detected from the data files when absent
+        #[structopt(long)]
+        engine: Option<String>,
+        ///json or binary
+        #[structopt(long, default_value = "json")]
+        format: ExportFormat,
+        ///file to read, standard input when absent
+        #[structopt(long)]
+        input: Option<PathBuf>,
+    },
+    #[structopt(name = "dump", about = "print every record of the kvs data file: offset, length, sequence, kind and key")]
+    Dump {
+        #[structopt(long, default_value = ".")]
+        dir: PathBuf,
+    },
+    #[structopt(name = "verify", about = "check that every record parses and their sequence numbers go up, without changing the files")]
+    Verify {
+        #[structopt(long, default_value = ".")]
+        dir: PathBuf,
+    },
+    #[structopt(name = "stats", about = "print the key count and the live and dead bytes of the kvs data file")]
+    Stats {
+        #[structopt(long, default_value = ".")]
+        dir: PathBuf,
+    },
+    #[structopt(name = "repair", about = "rewrite the records that parse into a new data file, keeping the old one as .data.corrupt or .data.corrupt.N")]
+    Repair {
+        #[structopt(long, default_value = ".")]
+        dir: PathBuf,
+    },
+    #[structopt(name = "migrate", about = "copy every live key-value pair into an empty directory of another engine")]
+    Migrate {
+        #[structopt(long)]
+        from: String,
+        #[structopt(long)]
+        to: String,
+        #[structopt(long, default_value = ".")]
+        dir: PathBuf,
+        #[structopt(long)]
+        dest: PathBuf,
+    },
+}
+
+fn main() -> Result<()> {
+    match Opt::from_args() {
+        Opt::Restore { backup, dir } => {
+            restore(&backup, &dir)?;
+            eprintln!("restored {} into {}", backup.display(), dir.display());
+        }
+        Opt::Export { dir, engine, format, output } => {
+            let mut engine = open_engine(engine, &dir)?;
+            let count = match output {
+                Some(path) => export(engine.as_mut(), format, File::create(path)?)?,
+                None => export(engine.as_mut(), format, stdout())?,
+            };
+            eprintln!("exported {} pairs", count);
+        }
+        Opt::Import { dir, engine, format, input } => {
+            let mut engine = open_engine(engine, &dir)?;
+            let count = match input {
+                Some(path) => import(engine.as_mut(), format, File::open(path)?)?,
+                None => import(engine.as_mut(), format, stdin())?,
+            };
+            eprintln!("imported {} pairs", count);
+        }
+        Opt::Dump { dir } => {
+            for entry in inspect::dump(&dir)? {
+                match entry {
+                    Entry::Record { offset, len, sequence, kind, key } => {
+                        let kind = match kind {
+                            RecordKind::Set => "set",
+                            RecordKind::Remove => "tombstone",
+                            RecordKind::Merge => "merge",
+                        };
+                        println!("{}\t{}\t{}\t{}\t{}", offset, len, sequence, kind, String::from_utf8_lossy(&key));
+                    }
+                    Entry::Corrupt { offset, len } => println!("{}\t{}\t-\tcorrupt", offset, len),
+                }
+            }
+        }
+        Opt::Verify { dir } => {
+            let problems = inspect::verify(&dir)?;
+            for problem in problems.iter() {
+                println!("{}", problem);
+            }
+            if !problems.is_empty() {
+                exit(1);
+            }
+            println!("ok");
+        }
+        Opt::Stats { dir } => {
+            let stats = inspect::stats(&dir)?;
+            println!("records: {}", stats.records);
+            println!("keys: {}", stats.keys);
+            println!("live bytes: {}", stats.live_bytes);
+            println!("dead bytes: {}", stats.dead_bytes);
+            println!("corrupt bytes: {}", stats.corrupt_bytes);
+        }
+        Opt::Repair { dir } => {
+            let report = inspect::repair(&dir)?;
+            let corrupt = report.corrupt.file_name().unwrap_or_default().to_string_lossy();
+            println!("kept {} records, dropped {} bytes, the old log is kept as {}", report.kept, report.dropped_bytes, corrupt);
+        }
+        Opt::Migrate { from, to, dir, dest } => {
+            if dest.is_dir() && fs::read_dir(&dest)?.next().is_some() {
+                return Err(Error::DirectoryNotEmptyError);
+            }
+            fs::create_dir_all(&dest)?;
+            let mut source = open_engine(Some(from), &dir)?;
+            let mut target = open_engine(Some(to), &dest)?;
+            let count = migrate(source.as_mut(), target.as_mut())?;
+            eprintln!("migrated {} pairs into {}", count, dest.display());
+        }
+    }
+    Ok(())
+}
+
+///open the named engine, or the one whose data files are in `dir`, refusing the data files of the other one
+fn open_engine(engine: Option<String>, dir: &Path) -> Result<Box<dyn KvsEngine>> {
+    let kvs_exist = dir.join(".data").is_file();
+    let sled_exist = dir.join("db").is_file();
+    let engine = engine.unwrap_or_else(|| if sled_exist { "sled".to_owned() } else { "kvs".to_owned() });
+    if (engine == "kvs" && sled_exist) || (engine == "sled" && kvs_exist) {
+        return Err(Error::InvalidEngineError);
+    }
+    match engine.as_str() {
+        "kvs" => Ok(Box::new(KvStore::open(dir)?)),
+        "sled" => Ok(Box::new(SledKvsEngine::open(dir)?)),
+        _ => Err(Error::InvalidEngineError),
+    }
+}
@@ -0,0 +1,92 @@
+use std::net::{SocketAddr, TcpStream};
+use crate::err::{Result, Error};
+use crate::utils::Command;
+use crate::kvs::{Transaction, TransactionTarget, At, Version, Event, Stats};
+
+///talks to a kvs-server, every request opens its own connection as the server expects
+pub struct KvsClient {
+    addr: SocketAddr,
+}
+
+impl KvsClient {
+    pub fn new(addr: SocketAddr) -> Self {
+        KvsClient { addr }
+    }
+
+    ///send one command and wait for the response
+    pub fn request(&self, cmd: &Command) -> Result<Command> {
+        let stream = TcpStream::connect(self.addr).map_err(|_| Error::ConnectFailedError)?;
+        serde_json::to_writer(stream.try_clone()?, cmd)?;
+        Ok(serde_json::from_reader(stream)?)
+    }
+
+    ///the value `key` had at `at`
+    pub fn get_at(&self, key: Vec<u8>, at: At) -> Result<Option<Vec<u8>>> {
+        match self.request(&Command::GetAt(key, at))? {
+            Command::Ok(value) => Ok(value),
+            Command::Err(msg) => Err(Error::ServerError(msg)),
+            _ => Err(Error::InternalError),
+        }
+    }
+
+    ///the writes under `prefix` from now on, the connection stays open while the iterator is alive
+    pub fn watch(&self, prefix: Vec<u8>) -> Result<impl Iterator<Item=Result<Event>>> {
+        self.stream(&Command::Watch(prefix))
+    }
+
+    ///every mutation after `since` in order and then the live ones, a consumer persists the sequence
+    ///number of the last event it handled to resume from it
+    pub fn changes(&self, since: u64) -> Result<impl Iterator<Item=Result<Event>>> {
+        self.stream(&Command::Changes(since))
+    }
+
+    ///send a command answered with events until the connection closes
+    fn stream(&self, cmd: &Command) -> Result<impl Iterator<Item=Result<Event>>> {
+        let stream = TcpStream::connect(self.addr).map_err(|_| Error::ConnectFailedError)?;
+        serde_json::to_writer(stream.try_clone()?, cmd)?;
+        let responses = serde_json::Deserializer::from_reader(stream).into_iter::<Command>();
+        Ok(responses.map(|response| match response? {
+            Command::Event(event) => Ok(event),
+            Command::Err(msg) => Err(Error::ServerError(msg)),
+            _ => Err(Error::InternalError),
+        }))
+    }
+
+    ///statistics of the engine behind the server
+    pub fn stats(&self) -> Result<Stats> {
+        match self.request(&Command::Stats)? {
+            Command::EngineStats(stats) => Ok(stats),
+            Command::Err(msg) => Err(Error::ServerError(msg)),
+            _ => Err(Error::InternalError),
+        }
+    }
+
+    ///the retained versions of `key`, oldest first
+    pub fn history(&self, key: Vec<u8>) -> Result<Vec<Version>> {
+        match self.request(&Command::History(key))? {
+            Command::Versions(versions) => Ok(versions),
+            Command::Err(msg) => Err(Error::ServerError(msg)),
+            _ => Err(Error::InternalError),
+        }
+    }
+}
+
+///lets `Transaction` and `run_transaction` work against a server
+impl TransactionTarget for KvsClient {
+    fn get_versioned(&mut self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)> {
+        match self.request(&Command::TxnGet(key))? {
+            Command::Versioned(value, version) => Ok((value, version)),
+            Command::Err(msg) => Err(Error::ServerError(msg)),
+            _ => Err(Error::InternalError),
+        }
+    }
+
+    fn commit(&mut self, txn: Transaction) -> Result<()> {
+        match self.request(&Command::TxnCommit(txn))? {
+            Command::Ok(_) => Ok(()),
+            Command::TxnConflict => Err(Error::TransactionConflictError),
+            Command::Err(msg) => Err(Error::ServerError(msg)),
+            _ => Err(Error::InternalError),
+        }
+    }
+}
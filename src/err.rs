@@ -1,23 +1,52 @@
-use failure::Fail;
+use std::fmt;
 use std::string::FromUtf8Error;
 
-#[derive(Debug,Fail)]
+#[derive(Debug)]
 pub enum Error{
-    #[fail(display="open data base log dir err")]
     OpenLogDirError,
-    #[fail(display="serializing data to log file meets err")]
     SerializingError,
-    #[fail(display="the specified path is not dir")]
     InvalidDirectoryPath,
-    #[fail(display="internal err")]
     InternalError,
-    #[fail(display="key not found")]
     KeyNotFoundError,
-    #[fail(display="can not connect to specified ip addr")]
     ConnectFailedError,
-    #[fail(display="specified engine not match to data file")]
     InvalidEngineError,
+    InvalidNumberError,
+    NoMergeOperatorError,
+    InvalidEncodingError,
+    TransactionConflictError,
+    UnsupportedError,
+    SequenceUnavailableError,
+    DirectoryNotEmptyError,
+    MmapUnavailableError,
+    SnapshotTooLargeError,
+    KeyCollisionError,
+    ServerError(String),
 }
+impl fmt::Display for Error{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::OpenLogDirError => write!(f, "open data base log dir err"),
+            Error::SerializingError => write!(f, "serializing data to log file meets err"),
+            Error::InvalidDirectoryPath => write!(f, "the specified path is not dir"),
+            Error::InternalError => write!(f, "internal err"),
+            Error::KeyNotFoundError => write!(f, "key not found"),
+            Error::ConnectFailedError => write!(f, "can not connect to specified ip addr"),
+            Error::InvalidEngineError => write!(f, "specified engine not match to data file"),
+            Error::InvalidNumberError => write!(f, "value is not a valid integer"),
+            Error::NoMergeOperatorError => write!(f, "no merge operator registered"),
+            Error::InvalidEncodingError => write!(f, "input is not valid in the given format"),
+            Error::TransactionConflictError => write!(f, "transaction conflict, a key it read has changed"),
+            Error::UnsupportedError => write!(f, "operation not supported by this engine"),
+            Error::SequenceUnavailableError => write!(f, "the requested sequence number is no longer retained"),
+            Error::DirectoryNotEmptyError => write!(f, "the destination directory is not empty"),
+            Error::MmapUnavailableError => write!(f, "memory-mapped reads are only available on the local disk, not with a custom vfs"),
+            Error::SnapshotTooLargeError => write!(f, "the snapshot would copy more data into memory than allowed"),
+            Error::KeyCollisionError => write!(f, "another key has the same hash in the index, the key cannot be written"),
+            Error::ServerError(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+impl std::error::Error for Error{}
 impl From<std::io::Error> for Error{
     fn from(_: std::io::Error) -> Self {
         Error::OpenLogDirError
@@ -33,4 +62,17 @@ impl From<FromUtf8Error> for Error{
         Error::InternalError
     }
 }
+impl From<sled::Error> for Error{
+    fn from(_: sled::Error) -> Self {
+        Error::InternalError
+    }
+}
 pub type Result<T> = std::result::Result<T,Error>;
+
+///returned by a compare-and-swap whose expected value does not match the stored one
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompareAndSwapError<V = String> {
+    ///the value stored when the swap was attempted, `None` if the key is absent
+    pub current: Option<V>,
+}
+pub type CompareAndSwapResult<V = String> = std::result::Result<(), CompareAndSwapError<V>>;
@@ -0,0 +1,45 @@
+use crate::err::{Result, Error};
+use crate::kvs::vfs::{self, Vfs, DiskVfs};
+use std::fs;
+use std::path::Path;
+
+///create the destination of a backup or restore, it must not hold anything yet
+pub(crate) fn prepare_dest(vfs: &dyn Vfs, dest: &Path) -> Result<()> {
+    vfs.create_dir_all(dest)?;
+    if !vfs.read_dir(dest)?.is_empty() {
+        return Err(Error::DirectoryNotEmptyError);
+    }
+    Ok(())
+}
+
+///share an immutable file with the backup, copying it when the destination is on another file system
+pub(crate) fn link_or_copy(vfs: &dyn Vfs, from: &Path, to: &Path) -> Result<()> {
+    if vfs.hard_link(from, to).is_err() {
+        vfs::copy(vfs, from, to)?;
+    }
+    Ok(())
+}
+
+///copy a backup made by `KvsEngine::backup` into `dest`, which an engine of the same kind can open afterwards.
+///the files are copied rather than linked so the backup stays untouched by the restored store
+pub fn restore(backup: &Path, dest: &Path) -> Result<()> {
+    if !backup.join(".data").is_file() && !backup.join("db").is_file() {
+        return Err(Error::InvalidDirectoryPath);
+    }
+    prepare_dest(&DiskVfs, dest)?;
+    copy_dir(backup, dest)
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            fs::create_dir_all(&target)?;
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
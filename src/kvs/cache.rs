@@ -0,0 +1,187 @@
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+///bytes charged for an entry on top of its value, for the key and the bookkeeping
+const ENTRY_OVERHEAD: usize = 64;
+
+///most shards a cache is split into
+const SHARDS: usize = 16;
+
+///least capacity of a shard, a smaller cache is split into fewer shards so its entries are not
+///evicted long before the cache is full
+const SHARD_BYTES: usize = 64 << 10;
+
+///(data file generation, position of the latest record of a key), the value it stands for never changes
+pub(crate) type CacheKey = (u64, usize);
+
+///values read recently, split by key hash into shards of their own lock and capacity, each evicting
+///its least recently used entries first once they take more than its share of the capacity in bytes.
+///shared by the store and its snapshots, the counters outlive compactions
+pub(crate) struct ValueCache {
+    ///capacity of every shard
+    capacity: usize,
+    shards: Vec<Mutex<Lru>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Lru {
+    ///value and last use of every entry
+    entries: HashMap<CacheKey, (Option<Vec<u8>>, u64)>,
+    ///entries by last use, oldest first
+    uses: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    bytes: usize,
+}
+
+impl ValueCache {
+    pub(crate) fn new(capacity: usize) -> ValueCache {
+        let shards = (capacity / SHARD_BYTES).clamp(1, SHARDS);
+        ValueCache {
+            capacity: capacity / shards,
+            shards: (0..shards).map(|_| Mutex::default()).collect(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: CacheKey) -> &Mutex<Lru> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    ///the cached value, which is `Some(None)` for a key a merge operator removed
+    pub(crate) fn get(&self, key: CacheKey) -> Option<Option<Vec<u8>>> {
+        let mut guard = self.shard(key).lock().unwrap();
+        let lru = &mut *guard;
+        lru.tick += 1;
+        let tick = lru.tick;
+        let value = match lru.entries.get_mut(&key) {
+            Some((value, used)) => {
+                let previous = std::mem::replace(used, tick);
+                let value = value.clone();
+                lru.uses.remove(&previous);
+                lru.uses.insert(tick, key);
+                Some(value)
+            }
+            None => None,
+        };
+        let counter = if value.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    ///cache a value read from the data file, a value larger than the whole cache is not kept
+    pub(crate) fn insert(&self, key: CacheKey, value: Option<Vec<u8>>) {
+        let size = charge(&value);
+        if size > self.capacity {
+            return;
+        }
+        let mut lru = self.shard(key).lock().unwrap();
+        lru.remove(key);
+        while lru.bytes + size > self.capacity {
+            let oldest = match lru.uses.keys().next() {
+                Some(used) => lru.uses[used],
+                None => break,
+            };
+            lru.remove(oldest);
+        }
+        lru.tick += 1;
+        let tick = lru.tick;
+        lru.entries.insert(key, (value, tick));
+        lru.uses.insert(tick, key);
+        lru.bytes += size;
+    }
+
+    ///drop an entry whose record was superseded
+    pub(crate) fn remove(&self, key: CacheKey) {
+        self.shard(key).lock().unwrap().remove(key);
+    }
+
+    ///drop every entry, the data file they point into was replaced
+    pub(crate) fn clear(&self) {
+        for shard in self.shards.iter() {
+            let mut lru = shard.lock().unwrap();
+            lru.entries.clear();
+            lru.uses.clear();
+            lru.bytes = 0;
+        }
+    }
+
+    ///(hits, misses)
+    pub(crate) fn counters(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+}
+
+impl Lru {
+    fn remove(&mut self, key: CacheKey) {
+        if let Some((value, used)) = self.entries.remove(&key) {
+            self.uses.remove(&used);
+            self.bytes -= charge(&value);
+        }
+    }
+}
+
+fn charge(value: &Option<Vec<u8>>) -> usize {
+    value.as_ref().map_or(0, Vec::len) + ENTRY_OVERHEAD
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ValueCache, ENTRY_OVERHEAD, SHARDS, SHARD_BYTES};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_eviction() {
+        let cache = ValueCache::new(3 * (ENTRY_OVERHEAD + 10));
+        for position in 0..3 {
+            cache.insert((0, position), Some(vec![position as u8; 10]));
+        }
+        //reading the first entry makes the second one the least recently used
+        assert_eq!(cache.get((0, 0)), Some(Some(vec![0; 10])));
+        cache.insert((0, 3), Some(vec![3; 10]));
+        assert_eq!(cache.get((0, 1)), None);
+        assert_eq!(cache.get((0, 0)), Some(Some(vec![0; 10])));
+        assert_eq!(cache.get((0, 2)), Some(Some(vec![2; 10])));
+        assert_eq!(cache.get((1, 2)), None);
+
+        cache.insert((0, 4), Some(vec![4; 4 * ENTRY_OVERHEAD]));
+        assert_eq!(cache.get((0, 4)), None);
+        cache.insert((0, 5), None);
+        assert_eq!(cache.get((0, 5)), Some(None));
+        cache.remove((0, 5));
+        cache.clear();
+        assert_eq!(cache.get((0, 0)), None);
+        assert_eq!(cache.counters(), (4, 4));
+    }
+
+    #[test]
+    fn test_shards() {
+        assert_eq!(ValueCache::new(3 * (ENTRY_OVERHEAD + 10)).shards.len(), 1);
+        let cache = Arc::new(ValueCache::new(SHARDS * SHARD_BYTES));
+        assert_eq!(cache.shards.len(), SHARDS);
+
+        //readers on several threads fill the shards without going past their share of the capacity
+        let readers: Vec<_> = (0..4u64).map(|generation| {
+            let cache = cache.clone();
+            thread::spawn(move || {
+                for position in 0..1000 {
+                    cache.insert((generation, position), Some(vec![0; 1000]));
+                    cache.get((generation, position));
+                }
+            })
+        }).collect();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        let bytes: Vec<_> = cache.shards.iter().map(|shard| shard.lock().unwrap().bytes).collect();
+        assert!(bytes.iter().all(|bytes| *bytes <= SHARD_BYTES && *bytes > 0));
+    }
+}
@@ -0,0 +1,54 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+///when `KvStore` compacts on its own after a write, every condition has to hold.
+///`KvsEngine::compact` ignores it
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompactionPolicy {
+    ///outdated bytes needed before compacting
+    pub dead_bytes: usize,
+    ///outdated bytes needed as a fraction of the live ones, 0 leaves it to `dead_bytes`
+    pub dead_ratio: f64,
+    ///hours of the day (UTC) compaction may run in, from the first one up to the second excluded,
+    ///wrapping around midnight when the first is larger, any hour when they are equal
+    pub window: Option<(u32, u32)>,
+    ///never compact automatically
+    pub disabled: bool,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy {
+            dead_bytes: 1 << 21,
+            dead_ratio: 0.0,
+            window: None,
+            disabled: false,
+        }
+    }
+}
+
+impl CompactionPolicy {
+    ///whether a store with these outdated and live bytes should compact now
+    pub fn should_compact(&self, dead_bytes: usize, live_bytes: usize) -> bool {
+        self.should_compact_at(dead_bytes, live_bytes, current_hour())
+    }
+
+    pub(crate) fn should_compact_at(&self, dead_bytes: usize, live_bytes: usize, hour: u32) -> bool {
+        !self.disabled
+            && dead_bytes >= self.dead_bytes
+            && dead_bytes as f64 >= self.dead_ratio * live_bytes as f64
+            && self.window.is_none_or(|(start, end)| in_window(hour, start, end))
+    }
+}
+
+fn in_window(hour: u32, start: u32, end: u32) -> bool {
+    if start <= end {
+        start == end || (start <= hour && hour < end)
+    } else {
+        hour >= start || hour < end
+    }
+}
+
+fn current_hour() -> u32 {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    ((secs / 3600) % 24) as u32
+}
@@ -1,56 +1,298 @@
-use crate::err::{Result, Error};
-use std::collections::{HashMap, BTreeMap};
+use crate::err::{Result, Error, CompareAndSwapError, CompareAndSwapResult};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 use std::path::PathBuf;
 use serde::{Serialize, Deserialize};
-use std::fs::{OpenOptions, File};
-use crate::kvs::utils::open_file;
-use std::io::{BufReader, BufWriter, Write, Seek, SeekFrom, Read, BufRead};
-use std::iter::FromIterator;
-use crate::KvsEngine;
+use std::io::{BufReader, BufWriter, Write, Seek, SeekFrom, Read};
+use crate::{KvsEngine, Snapshot, Pairs};
+use crate::kvs::{MergeOperator, Options, CompactionPolicy, Vfs, VfsFile, DiskVfs, Transaction, Retention, Version, now_millis, Event, Watch, Watchers, Changes, Stats};
+use std::time::{Duration, Instant};
+use crate::kvs::segments;
+use crate::kvs::backup::prepare_dest;
+use std::path::Path;
+use crate::kvs::merge::add_to_counter;
+use crate::kvs::mmap::MappedFile;
+use crate::kvs::cache::ValueCache;
+use crate::kvs::keydir::{Keydir, Index};
 
-const DATA_FILE_SIZE: i32 = 1 << 22;
-const COMPACT_THRESHOLD: i32 = 1 << 21;
+///compaction writes into a file named after this prefix, the ones left by a crash are removed at open
+const COMPACTION_PREFIX: &str = ".data_tmp";
+
+///bytes of the data file replayed between two calls of `Options::recovery_progress`
+const PROGRESS_INTERVAL: usize = 64 << 20;
+
+type Reader = BufReader<Box<dyn VfsFile>>;
+type Writer = BufWriter<Box<dyn VfsFile>>;
+
+///points of a compaction after which tests crash it
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CompactionStep {
+    Copied,
+    Synced,
+    Archived,
+    Renamed,
+}
 
 ///A key-value database based on log structure,[bitcast](https://github.com/basho/bitcask/blob/develop/doc/bitcask-intro.pdf)
 /// is referred to.It append data to logfile and update the index in memory.when a large amount of data is out of date,
 /// logfile will be compressed.
 pub struct Database {
     dir: PathBuf,
-    index: BTreeMap<String, Index>,
-    file: File,
-    writer: BufWriter<File>,
-    reader: BufReader<File>,
+    vfs: Arc<dyn Vfs>,
+    ///shared with snapshots, writes copy it when a snapshot still holds it
+    index: Arc<Keydir>,
+    file: Box<dyn VfsFile>,
+    writer: Writer,
+    records: Records,
+    ///values read recently, keyed by where their latest record is in the data file of a generation
+    cache: Option<Arc<ValueCache>>,
+    ///bumped when compaction replaces the data file
+    generation: u64,
+    ///records replayed by `open` and how long it took
+    recovered_records: u64,
+    recovery: Duration,
+    ///bytes of the records the index points to, kept up to date so writes need not sum the index
+    live_len: usize,
     outdated_len: usize,
+    merge_operator: Option<MergeOperator>,
+    ///sequence number of the last record appended
+    sequence: u64,
+    ///cloned by every snapshot, compaction waits until no snapshot pins the data file
+    pins: Arc<()>,
+    retention: Option<Retention>,
+    ///superseded record chains and removals of every key, oldest first, only tracked with a retention
+    history: BTreeMap<Vec<u8>, Vec<Index>>,
+    watchers: Watchers,
+    ///records up to this sequence number were copied into the data file by the last compaction
+    base: u64,
+    ///sequence numbers of the removals since `removed_floor`, the versions of those keys while absent
+    removed: HashMap<Vec<u8>, u64>,
+    ///version of the other absent keys: the sequence number at open or at the last compaction, past
+    ///every removal `removed` no longer holds
+    removed_floor: u64,
+    retained_segments: usize,
+    compaction: CompactionPolicy,
+    reads: u64,
+    writes: u64,
+    compactions: u64,
+    last_compaction: Option<Duration>,
+    #[cfg(test)]
+    crash_at: Option<CompactionStep>,
 }
 
 impl KvsEngine for Database {
     ///inset a key-value mapping into database,it write data to disk firstly,then record the physical
     ///position in memory
-    fn set(&mut self, key: String, value: String) -> Result<()> {
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
 
         self.op_set(key, value)?;
-        self.compact()?;
+        self.maybe_compact()?;
         Ok(())
     }
 
-
     ///query data by given key
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        let value = match self.index.get(&key) {
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.reads += 1;
+        let value = match find(&self.index, &mut self.records, &key)? {
             None => None,
-            Some(index) => {
-                let raw_string = read_by_pos(&mut self.reader, index.start, index.end)?;
-                let log: Log = serde_json::from_str(raw_string.as_str())?;
-                Some(log.1.unwrap())
-            }
+            Some(index) => read_value(&mut self.records, self.cache.as_deref(), self.generation, index, self.merge_operator.as_ref())?
         };
         Ok(value)
     }
 
     ///remove data by given key
-    fn remove(&mut self, key: String) -> Result<()> {
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
         self.op_remove(key)?;
-        self.compact()?;
+        self.maybe_compact()?;
+        Ok(())
+    }
+
+    ///`&mut self` is the write lock of the database, so the read and the following write are atomic
+    fn compare_and_swap_bytes(&mut self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<CompareAndSwapResult<Vec<u8>>> {
+        let current = self.get_bytes(key.clone())?;
+        if current != expected {
+            return Ok(Err(CompareAndSwapError { current }));
+        }
+        match new {
+            Some(value) => self.set_bytes(key, value)?,
+            None if current.is_some() => self.remove_bytes(key)?,
+            None => {}
+        }
+        Ok(Ok(()))
+    }
+
+    fn increment_bytes(&mut self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let current = self.get_bytes(key.clone())?;
+        let value = add_to_counter(current.as_deref(), delta)?;
+        self.set_bytes(key, value.to_string().into_bytes())?;
+        Ok(value)
+    }
+
+    ///only the operand is appended to the log, it is folded into the value by `get` and `compact`
+    fn merge_bytes(&mut self, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        if self.merge_operator.is_none() {
+            return Err(Error::NoMergeOperatorError);
+        }
+        self.claim(&key)?;
+        let watched = if self.watchers.is_watching(&key) { Some(operand.clone()) } else { None };
+        let (start, len) = self.append_log(key.clone(), None, Some(operand))?;
+        self.writes += 1;
+        let version = self.sequence;
+        let (cache, generation) = (self.cache.as_deref(), self.generation);
+        self.live_len += len;
+        let indexes = Arc::make_mut(&mut self.index);
+        match indexes.get_mut(&key) {
+            Some(index) => {
+                if let Some(cache) = cache {
+                    cache.remove((generation, index.latest()));
+                }
+                index.push_operand(start, len);
+                index.version = version;
+            }
+            None => {
+                indexes.insert(key.clone(), Index::new(start, len, version));
+                self.removed.remove(&key);
+            }
+        }
+        if let Some(operand) = watched {
+            let value = self.get_bytes(key.clone())?;
+            self.watchers.notify_merge(version, key, operand, value);
+        }
+        self.maybe_compact()?;
+        Ok(())
+    }
+
+    ///the snapshot shares the current index and reads the data file through its own handle or the
+    ///shared memory map, later writes are appended behind the positions it knows about
+    fn snapshot(&mut self) -> Result<Box<dyn Snapshot>> {
+        let records = match &self.records {
+            Records::Mapped(map) => Records::Mapped(map.clone()),
+            Records::Buffered(_) => Records::Buffered(BufReader::new(self.vfs.open_read(&self.dir.join(".data"))?)),
+        };
+        Ok(Box::new(DatabaseSnapshot {
+            sequence: self.sequence,
+            index: self.index.clone(),
+            records,
+            cache: self.cache.clone(),
+            generation: self.generation,
+            merge_operator: self.merge_operator.clone(),
+            _pin: self.pins.clone(),
+        }))
+    }
+
+    ///the borrow keeps writers away, so the pairs are read straight from the index and the data file
+    fn scan(&mut self) -> Result<Pairs<'_>> {
+        Ok(pairs(&self.index, &mut self.records, self.merge_operator.as_ref()))
+    }
+
+    fn get_versioned(&mut self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)> {
+        let version = self.version(&key)?;
+        Ok((self.get_bytes(key)?, version))
+    }
+
+    ///the versions of the read keys are checked against the index and the writes are applied
+    ///without releasing `&mut self`, so no other operation can slip in between
+    fn commit(&mut self, txn: Transaction) -> Result<()> {
+        for (key, version) in txn.reads() {
+            if self.version(&key)? != version {
+                return Err(Error::TransactionConflictError);
+            }
+        }
+        for (key, value) in txn.into_writes() {
+            match value {
+                Some(value) => self.op_set(key, value)?,
+                None if find(&self.index, &mut self.records, &key)?.is_some() => self.op_remove(key)?,
+                None => {}
+            }
+        }
+        self.maybe_compact()?;
+        Ok(())
+    }
+
+    ///every record of a retained chain is a version, merge operands are folded one at a time
+    fn history_bytes(&mut self, key: Vec<u8>) -> Result<Vec<Version>> {
+        let mut result = vec![];
+        let retained = self.history.get(&key).into_iter().flatten();
+        for index in retained.chain(find(&self.index, &mut self.records, &key)?) {
+            result.extend(versions(&mut self.records, index, self.merge_operator.as_ref())?);
+        }
+        Ok(result)
+    }
+
+    ///events are sent from the write path once the record is appended
+    fn watch(&mut self, prefix: Vec<u8>) -> Result<Watch> {
+        Ok(self.watchers.add(prefix))
+    }
+
+    ///the retained segments and the data file are read through handles opened now, so a later compaction
+    ///does not move them away. records that a compaction copied are skipped by their sequence number,
+    ///every file only appends in order after its base
+    fn changes(&mut self, since: u64) -> Result<Changes> {
+        let retained = segments::list(self.vfs.as_ref(), &self.dir)?;
+        let oldest_base = retained.first().map_or(self.base, |segment| segment.base);
+        if since < oldest_base {
+            return Err(Error::SequenceUnavailableError);
+        }
+        let mut files = vec![];
+        for segment in retained.into_iter().filter(|segment| segment.last > since) {
+            files.push(self.vfs.open_read(&segment.path)?.take(u64::MAX));
+        }
+        self.writer.flush()?;
+        //only the records complete at this moment, the later ones arrive through the feed
+        let stored_len = self.file.size()?;
+        files.push(self.vfs.open_read(&self.dir.join(".data"))?.take(stored_len));
+        let live = self.watchers.add_feed();
+        let upto = self.sequence;
+        let mut last = since;
+        let stored = files.into_iter()
+            .flat_map(|file| serde_json::Deserializer::from_reader(BufReader::new(file)).into_iter::<Log>())
+            .filter_map(move |log| match log {
+                Err(e) => Some(Err(e.into())),
+                Ok(log) if log.3 > last && log.3 <= upto => {
+                    last = log.3;
+                    Some(Ok(log.into_event()))
+                }
+                Ok(_) => None,
+            });
+        Ok(Box::new(stored.chain(live.map(Ok))))
+    }
+
+    ///snapshots keep reading the replaced data file through the handle they opened
+    fn compact(&mut self) -> Result<()> {
+        self.rewrite()
+    }
+
+    fn stats(&mut self) -> Result<Stats> {
+        let (keys, live_bytes, dead_bytes) = self.usage();
+        let (cache_hits, cache_misses) = self.cache.as_ref().map_or((0, 0), |cache| cache.counters());
+        Ok(Stats {
+            keys: keys as u64,
+            live_bytes: live_bytes as u64,
+            dead_bytes: dead_bytes as u64,
+            segments: segments::list(self.vfs.as_ref(), &self.dir)?.len() as u64 + 1,
+            compactions: self.compactions,
+            last_compaction: self.last_compaction,
+            reads: self.reads,
+            writes: self.writes,
+            cache_hits,
+            cache_misses,
+            index_bytes: self.index.memory_usage() as u64,
+            recovered_records: self.recovered_records,
+            recovery: Some(self.recovery),
+        })
+    }
+
+    ///retained segments never change and are hard-linked, the data file only grows between compactions
+    ///and `&mut self` holds off writes, so copying its current length captures whole records
+    fn backup(&mut self, dest: &Path) -> Result<()> {
+        let vfs = self.vfs.as_ref();
+        prepare_dest(vfs, dest)?;
+        segments::backup(vfs, &self.dir, dest)?;
+        self.writer.flush()?;
+        let len = self.file.size()?;
+        let mut copy = vfs.create(&dest.join(".data"))?;
+        std::io::copy(&mut vfs.open_read(&self.dir.join(".data"))?.take(len), &mut copy)?;
+        copy.sync_all()?;
         Ok(())
     }
 }
@@ -58,108 +300,368 @@ impl KvsEngine for Database {
 impl Database {
     ///creating a new instance by given log dir
     pub fn open(path: impl Into<PathBuf> + Clone) -> Result<Self> {
-        let file = open_file(path.clone(), true, ".data")?;
+        Self::open_with(path, Options::default())
+    }
+
+    ///creating a new instance by given log dir and options
+    pub fn open_with(path: impl Into<PathBuf> + Clone, options: Options) -> Result<Self> {
+        let dir: PathBuf = path.into();
+        if options.mmap && options.vfs.is_some() {
+            return Err(Error::MmapUnavailableError);
+        }
+        let vfs = options.vfs.clone().unwrap_or_else(|| Arc::new(DiskVfs));
+        for path in vfs.read_dir(&dir)? {
+            if path.file_name().is_some_and(|name| name.to_string_lossy().starts_with(COMPACTION_PREFIX)) {
+                vfs.remove_file(&path)?;
+            }
+        }
+        let file = vfs.open_append(&dir.join(".data"))?;
+        let started = Instant::now();
+        let total_len = file.size()?;
         let reader = BufReader::new(file.try_clone()?);
         let mut outdated_len = 0;
-        let idxs = serde_json::Deserializer::from_reader(reader)
-            .into_iter::<Log>()
-
-            .map(|pair| pair.unwrap())
-            //so ugly
-            .map(|log| (log.0.clone(), serde_json::to_string(&log).unwrap().len(), log.1.is_none()))
-
-            .collect::<Vec<(String, usize, bool)>>();
-        let mut map: BTreeMap<String, Index> = BTreeMap::new();
-        map_adjacent(0, idxs)
-            .into_iter()
-            .for_each(|(index, delete)|
-                if delete {
-                    let removed_data = map.remove(&index.key).unwrap();
-                    outdated_len += index.end - index.start + removed_data.end - removed_data.start;
-                } else {
-                    map.insert(index.key.clone(), index);
+        let mut sequence = 0;
+        let mut replayed = 0;
+        let mut map = Keydir::new(options.hashed_keys);
+        let mut history: BTreeMap<Vec<u8>, Vec<Index>> = BTreeMap::new();
+        let mut retire = |key: &[u8], index: Index| if options.retention.is_some() {
+            history.entry(key.to_vec()).or_default().push(index);
+        };
+        let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<Log>();
+        let (mut start, mut reported) = (0, 0);
+        //lengths come from the stream position, records written by older versions may serialize differently now
+        while let Some(log) = stream.next() {
+            let log = match log {
+                Ok(log) => log,
+                //the last record was cut short by a crash in the middle of its append
+                Err(e) if e.is_eof() => break,
+                Err(e) => return Err(e.into()),
+            };
+            let end = stream.byte_offset();
+            let index = Index::new(start, end - start, log.3);
+            sequence = sequence.max(log.3);
+            replayed += 1;
+            start = end;
+            match log.kind() {
+                LogKind::Remove => {
+                    //the set a removal follows may have been pruned from the history by compaction
+                    if let Some(removed_data) = map.remove(&log.0) {
+                        outdated_len += removed_data.len();
+                        retire(&log.0, removed_data);
+                    }
+                    outdated_len += index.len();
+                    retire(&log.0, index);
+                }
+                LogKind::Set => {
+                    if let Some(replaced) = map.insert(log.0.clone(), index) {
+                        outdated_len += replaced.len();
+                        retire(&log.0, replaced);
+                    }
+                }
+                LogKind::Merge => match map.get_mut(&log.0) {
+                    Some(base) => {
+                        base.push_operand(index.start(), index.len());
+                        base.version = index.version;
+                    }
+                    None => drop(map.insert(log.0, index)),
+                }
+            }
+            if let Some(progress) = &options.recovery_progress {
+                if end - reported >= PROGRESS_INTERVAL {
+                    reported = end;
+                    progress(end as u64, total_len, 0);
                 }
-            );
+            }
+        }
+        let torn_len = total_len - start as u64;
+        if torn_len > 0 {
+            file.set_len(start as u64)?;
+            file.sync_all()?;
+        }
+        if let Some(progress) = &options.recovery_progress {
+            progress(start as u64, total_len, torn_len);
+        }
+        //compaction may have dropped the latest records, the sequence never goes back past its base
+        let base = segments::read_base(vfs.as_ref(), &dir)?;
+        let sequence = sequence.max(base);
+        let live_len = map.values().map(Index::len).sum();
+        let records = if options.mmap {
+            Records::Mapped(MappedFile::open(&dir.join(".data"))?)
+        } else {
+            Records::Buffered(BufReader::new(file.try_clone()?))
+        };
         Ok(Database {
-            dir: path.into(),
-            index: map,
+            dir: dir.clone(),
+            vfs: vfs.clone(),
+            index: Arc::new(map),
             file: file.try_clone()?,
             writer: BufWriter::new(file.try_clone()?),
-            reader: BufReader::new(file.try_clone()?),
+            records,
+            cache: (options.cache_bytes > 0).then(|| Arc::new(ValueCache::new(options.cache_bytes))),
+            generation: 0,
+            recovered_records: replayed,
+            recovery: started.elapsed(),
+            live_len,
             outdated_len,
+            merge_operator: options.merge_operator,
+            sequence,
+            pins: Arc::new(()),
+            retention: options.retention,
+            history,
+            watchers: Watchers::default(),
+            base,
+            removed: HashMap::new(),
+            removed_floor: sequence,
+            retained_segments: options.retained_segments,
+            compaction: options.compaction,
+            reads: 0,
+            writes: 0,
+            compactions: 0,
+            last_compaction: None,
+            #[cfg(test)]
+            crash_at: None,
         })
     }
-    fn compact(&mut self) -> Result<()> {
-        if self.outdated_len < COMPACT_THRESHOLD as usize {
+    ///compact when the policy asks for it, compaction waits while a snapshot pins the data file
+    fn maybe_compact(&mut self) -> Result<()> {
+        let (_, live_len, outdated_len) = self.usage();
+        if !self.compaction.should_compact(outdated_len, live_len) || Arc::strong_count(&self.pins) > 1 {
             return Ok(());
         }
-        let new_file = open_file(&self.dir, true, ".data_tmp")?;
-        let mut new_index = BTreeMap::new();
+        self.rewrite()
+    }
+
+    ///the new log is complete and durable before it replaces the old one, a crash at any point leaves
+    ///either of them in place. the in-memory state only switches once the rename succeeded
+    fn rewrite(&mut self) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.{}.{}", COMPACTION_PREFIX, std::process::id(), self.sequence));
+        let result = self.rewrite_into(&tmp);
+        if result.is_err() {
+            let _ = self.vfs.remove_file(&tmp);
+        }
+        result
+    }
+
+    fn rewrite_into(&mut self, tmp: &Path) -> Result<()> {
+        let started = Instant::now();
+        let new_file = self.vfs.create_new(tmp)?;
         let mut new_writer = BufWriter::new(new_file.try_clone()?);
-        let mut old_reader = BufReader::new(self.file.try_clone()?);
-        self.index.values()
-            .map(|index| (index.key.clone(), read_by_pos(&mut old_reader, index.start, index.end)))
-            .map(|strs| (strs.0, append_serialized(&mut new_writer, strs.1.unwrap()).unwrap()))
-            .for_each(|(key, (start, len))| drop(new_index.insert(key.clone(), Index {
-                key,
-                start,
-                end: start + len,
-            })));
-        self.index = new_index;
+        let mut old_records = Records::Buffered(BufReader::new(self.file.try_clone()?));
+        let mut new_history = BTreeMap::new();
+        if let Some(retention) = self.retention {
+            for (key, chains) in self.history.iter() {
+                let current = self.index.get(key);
+                let kept = retained(&mut old_records, chains, current, retention)?;
+                let copied = kept.iter()
+                    .map(|index| copy_chain(&mut old_records, &mut new_writer, index))
+                    .collect::<Result<Vec<_>>>()?;
+                if !copied.is_empty() {
+                    new_history.insert(key.clone(), copied);
+                }
+            }
+        }
+        let new_index = self.index.try_map(|index| -> Result<Option<Index>> {
+            //operands are kept as they are while versions are retained, so the history stays exact
+            if !index.has_operands() || self.retention.is_some() {
+                return Ok(Some(copy_chain(&mut old_records, &mut new_writer, index)?));
+            }
+            //otherwise merge operands are folded so that only plain values are carried over,
+            //the folded value takes the sequence number and time of the latest operand
+            let key = old_records.log(index.start(), index.end())?.0;
+            let latest = match versions(&mut old_records, index, self.merge_operator.as_ref())?.pop() {
+                Some(Version { sequence, timestamp, value: Some(value) }) => Log(key, Some(value), None, sequence, timestamp),
+                _ => return Ok(None),
+            };
+            let (start, len) = append_serialized(&mut new_writer, serde_json::to_string(&latest)?)?;
+            Ok(Some(Index::new(start, len, index.version)))
+        })?;
+        new_writer.flush()?;
+        self.reached(CompactionStep::Copied);
+        new_file.sync_all()?;
+        self.reached(CompactionStep::Synced);
+        let new_records = match self.records {
+            Records::Mapped(_) => Records::Mapped(MappedFile::open(tmp)?),
+            Records::Buffered(_) => Records::Buffered(BufReader::new(new_file.try_clone()?)),
+        };
+        segments::archive(self.vfs.as_ref(), &self.dir, &self.dir.join(".data"), self.base, self.sequence, self.sequence, self.retained_segments)?;
+        self.reached(CompactionStep::Archived);
+        self.vfs.rename(tmp, &self.dir.join(".data"))?;
+        self.live_len = new_index.values().map(Index::len).sum();
+        self.index = Arc::new(new_index);
+        self.history = new_history;
         self.writer = new_writer;
-        self.writer.flush()?;
-        self.reader = BufReader::new(new_file.try_clone()?);
+        self.records = new_records;
         self.file = new_file;
+        self.generation += 1;
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
         self.outdated_len = 0;
-        std::fs::rename(self.dir.join(".data_tmp"), self.dir.join(".data"))?;
+        self.base = self.sequence;
+        self.removed.clear();
+        self.removed_floor = self.sequence;
+        self.reached(CompactionStep::Renamed);
+        self.vfs.sync_dir(&self.dir)?;
+        self.compactions += 1;
+        self.last_compaction = Some(started.elapsed());
 
         Ok(())
     }
-    fn op_set(&mut self, key: String, value: String) -> Result<()> {
-        let (start, len) = self.append_to_file(key.clone(), Some(value))?;
-        self.insert_or_replace_index(key, start, len)?;
+    #[cfg(test)]
+    fn reached(&self, step: CompactionStep) {
+        if self.crash_at == Some(step) {
+            panic!("crash injected after {:?}", step);
+        }
+    }
+
+    #[cfg(not(test))]
+    fn reached(&self, _step: CompactionStep) {}
+
+    ///(key count, bytes of the live records, bytes of the outdated ones)
+    pub(crate) fn usage(&self) -> (usize, usize, usize) {
+        (self.index.len(), self.live_len, self.outdated_len)
+    }
+
+    ///read back every record the index points to and describe the ones that do not match it
+    #[cfg(test)]
+    pub(crate) fn check_index(&mut self) -> Result<Vec<String>> {
+        let mut problems = vec![];
+        for index in self.index.values() {
+            for (i, (start, end)) in index.chain().enumerate() {
+                let log = match self.records.log(start, end) {
+                    Ok(log) => log,
+                    _ => {
+                        problems.push(format!("record at {}..{} does not parse", start, end));
+                        continue;
+                    }
+                };
+                //the index found under the key of the record has to be this one, whether keys or their hashes are kept
+                if !self.index.get(&log.0).is_some_and(|found| std::ptr::eq(found, index)) {
+                    problems.push(format!("record at {} holds key {:?} but the index of that key points elsewhere", start, String::from_utf8_lossy(&log.0)));
+                }
+                let expected = if i == 0 { log.kind() != LogKind::Remove } else { log.kind() == LogKind::Merge };
+                if !expected || log.3 > index.version {
+                    problems.push(format!("record at {} of key {:?} is not the one the index expects", start, String::from_utf8_lossy(&log.0)));
+                }
+            }
+        }
+        Ok(problems)
+    }
+
+    ///the version a transaction reads for `key`, an absent key keeps the one of its removal so that
+    ///a key set and removed again in between is a change too
+    fn version(&mut self, key: &[u8]) -> Result<u64> {
+        Ok(match find(&self.index, &mut self.records, key)? {
+            Some(index) => index.version,
+            None => self.removed.get(key).copied().unwrap_or(self.removed_floor),
+        })
+    }
+
+    ///refuse a key whose hash already points at the record of another key, so that the index never
+    ///mixes the records of two keys
+    fn claim(&mut self, key: &[u8]) -> Result<()> {
+        if self.index.contains_key(key) && find(&self.index, &mut self.records, key)?.is_none() {
+            return Err(Error::KeyCollisionError);
+        }
+        Ok(())
+    }
+
+    ///the index and the counters only change once the record is in the log
+    fn op_set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.claim(&key)?;
+        let watched = if self.watchers.is_watching(&key) { Some(value.clone()) } else { None };
+        let (start, len) = self.append_log(key.clone(), Some(value), None)?;
+        self.writes += 1;
+        self.insert_or_replace_index(key.clone(), start, len)?;
+        if let Some(value) = watched {
+            self.watchers.notify(Event::Set { sequence: self.sequence, key, value });
+        }
         Ok(())
     }
-    fn op_remove(&mut self, key: String) -> Result<()> {
+    fn op_remove(&mut self, key: Vec<u8>) -> Result<()> {
+        if find(&self.index, &mut self.records, &key)?.is_none() {
+            return Err(Error::KeyNotFoundError);
+        }
+        let (start, len) = self.append_log(key.clone(), None, None)?;
+        self.writes += 1;
         let index = self.remove_index(key.clone())?;
-        let (_, len) = self.append_to_file(key, None)?;
-        self.outdated_len += index.end - index.start + len;
+        self.evict(&index);
+        self.outdated_len += index.len() + len;
+        self.retire(&key, index);
+        let version = self.sequence;
+        self.retire(&key, Index::new(start, len, version));
+        self.removed.insert(key.clone(), version);
+        self.watchers.notify(Event::Remove { sequence: version, key });
         Ok(())
     }
 
-    fn append_to_file(&mut self, key: String, value: Option<String>) -> Result<(usize, usize)> {
-        let text = serde_json::to_string(&Log(key.clone(), value))?;
-        append_serialized(&mut self.writer, text)
+    ///drop the cached value of a superseded chain
+    fn evict(&self, index: &Index) {
+        if let Some(cache) = &self.cache {
+            cache.remove((self.generation, index.latest()));
+        }
     }
 
-    fn insert_or_replace_index(&mut self, key: String, start: usize, len: usize) -> Result<()> {
-        match self.index.insert(key.clone(), Index {
-            key,
-            start,
-            end: start + len,
-        }) {
-            None => {}
+    ///keep a superseded chain or a removal as history when versions are retained
+    fn retire(&mut self, key: &[u8], index: Index) {
+        if self.retention.is_some() {
+            self.history.entry(key.to_vec()).or_default().push(index);
+        }
+    }
+
+    ///append a record stamped with the next sequence number
+    fn append_log(&mut self, key: Vec<u8>, value: Option<Vec<u8>>, operand: Option<Vec<u8>>) -> Result<(usize, usize)> {
+        let text = serde_json::to_string(&Log(key, value, operand, self.sequence + 1, now_millis()))?;
+        //the index keeps record lengths in 32 bits
+        if text.len() > u32::MAX as usize {
+            return Err(Error::SerializingError);
+        }
+        let end = self.file.size()?;
+        match append_serialized(&mut self.writer, text) {
+            Ok(position) => {
+                self.sequence += 1;
+                Ok(position)
+            }
+            Err(e) => {
+                //a partly written record would break the log, it is cut off with whatever the writer still buffers
+                let _ = std::mem::replace(&mut self.writer, BufWriter::new(self.file.try_clone()?)).into_parts();
+                self.file.set_len(end)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn insert_or_replace_index(&mut self, key: Vec<u8>, start: usize, len: usize) -> Result<()> {
+        self.live_len += len;
+        match Arc::make_mut(&mut self.index).insert(key.clone(), Index::new(start, len, self.sequence)) {
+            None => drop(self.removed.remove(&key)),
             Some(index) => {
-                self.outdated_len += index.end - index.start;
+                self.evict(&index);
+                self.live_len -= index.len();
+                self.outdated_len += index.len();
+                self.retire(&key, index);
             }
         };
         Ok(())
     }
-    fn remove_index(&mut self, key: String) -> Result<Index> {
-        return match self.index.remove(&key) {
+    fn remove_index(&mut self, key: Vec<u8>) -> Result<Index> {
+        match Arc::make_mut(&mut self.index).remove(&key) {
             None => Err(Error::KeyNotFoundError),
-            Some(index) => Ok(index)
-        };
+            Some(index) => {
+                self.live_len -= index.len();
+                Ok(index)
+            }
+        }
     }
 }
 
-fn append_serialized(writer:&mut BufWriter<File>, serialized: String) -> Result<(usize, usize)> {
+fn append_serialized(writer:&mut Writer, serialized: String) -> Result<(usize, usize)> {
     let start = writer.seek(SeekFrom::End(0))? as usize;
-    let len = writer.write(serialized.as_bytes())?;
+    writer.write_all(serialized.as_bytes())?;
     writer.flush()?;
-    Ok((start, len))
+    Ok((start, serialized.len()))
 }
-fn read_by_pos(reader: &mut BufReader<File>, start: usize, end: usize) -> Result<String> {
+fn read_by_pos(reader: &mut Reader, start: usize, end: usize) -> Result<String> {
     let len = end - start;
     let mut buffer = vec![0; len];
     reader.seek(SeekFrom::Start(start as u64))?;
@@ -167,109 +669,1021 @@ fn read_by_pos(reader: &mut BufReader<File>, start: usize, end: usize) -> Result
     Ok(String::from_utf8(buffer)?)
 }
 
-///(key,length,is_deleted)->(key,start,end,is_deleted)
-fn map_adjacent(start_pos: usize, collection: Vec<(String, usize, bool)>) -> Vec<(Index, bool)> {
-    return match collection.split_first() {
-        None => vec![],
-        Some(((str, len, delete), tail)) =>
-            vec![vec![(Index { key: str.to_string(), start: start_pos, end: start_pos + len }, delete.clone())], map_adjacent(start_pos + len, tail.to_vec())].concat()
+///where records are read from, a reader seeking to them or a memory map of the data file
+enum Records {
+    Buffered(Reader),
+    Mapped(MappedFile),
+}
+
+impl Records {
+    ///the record at `start..end` as it is written
+    fn raw(&mut self, start: usize, end: usize) -> Result<String> {
+        match self {
+            Records::Buffered(reader) => read_by_pos(reader, start, end),
+            Records::Mapped(map) => Ok(String::from_utf8(map.get(start, end)?.to_vec())?),
+        }
+    }
+
+    ///the record at `start..end`, parsed in place when it is mapped
+    fn log(&mut self, start: usize, end: usize) -> Result<Log> {
+        match self {
+            Records::Buffered(reader) => Ok(serde_json::from_str(&read_by_pos(reader, start, end)?)?),
+            Records::Mapped(map) => Ok(serde_json::from_slice(&map.get(start, end)?)?),
+        }
+    }
+}
+
+///the index of `key`. a hashed keydir may hold the index of another key under the same hash, the key
+///of its record is compared then, which costs a read
+fn find<'a>(keydir: &'a Keydir, records: &mut Records, key: &[u8]) -> Result<Option<&'a Index>> {
+    match keydir.get(key) {
+        Some(index) if matches!(keydir, Keydir::Hashed(..)) && records.log(index.start(), index.end())?.0 != key => Ok(None),
+        found => Ok(found),
+    }
+}
+
+///read the record chain of an index and fold its merge operands into the value
+fn fold(records: &mut Records, index: &Index, merge_operator: Option<&MergeOperator>) -> Result<Option<Vec<u8>>> {
+    let Log(key, value, operand, ..) = records.log(index.start(), index.end())?;
+    if operand.is_none() && !index.has_operands() {
+        return Ok(value);
+    }
+    let merge_operator = merge_operator.ok_or(Error::NoMergeOperatorError)?;
+    let mut value = match operand {
+        None => value,
+        Some(operand) => merge_operator.merge(&key, None, &operand),
     };
+    for (start, end) in index.operands() {
+        let log = records.log(start, end)?;
+        value = merge_operator.merge(&key, value.as_deref(), &log.2.unwrap_or_default());
+    }
+    Ok(value)
 }
 
+///`fold` through the cache when there is one
+fn read_value(records: &mut Records, cache: Option<&ValueCache>, generation: u64, index: &Index, merge_operator: Option<&MergeOperator>) -> Result<Option<Vec<u8>>> {
+    let cache = match cache {
+        None => return fold(records, index, merge_operator),
+        Some(cache) => cache,
+    };
+    let key = (generation, index.latest());
+    if let Some(value) = cache.get(key) {
+        return Ok(value);
+    }
+    let value = fold(records, index, merge_operator)?;
+    cache.insert(key, value.clone());
+    Ok(value)
+}
+
+///read every record of the chain of an index, the value after each of them is a version
+fn versions(records: &mut Records, index: &Index, merge_operator: Option<&MergeOperator>) -> Result<Vec<Version>> {
+    let mut result = vec![];
+    let mut value = None;
+    for (start, end) in index.chain() {
+        let log = records.log(start, end)?;
+        value = match log.2 {
+            None => log.1,
+            Some(operand) => merge_operator.ok_or(Error::NoMergeOperatorError)?.merge(&log.0, value.as_deref(), &operand),
+        };
+        result.push(Version { sequence: log.3, timestamp: log.4, value: value.clone() });
+    }
+    Ok(result)
+}
+
+///the superseded chains of a key that the retention still covers
+fn retained<'a>(records: &mut Records, chains: &'a [Index], current: Option<&Index>, retention: Retention) -> Result<&'a [Index]> {
+    match retention {
+        Retention::Versions(count) => Ok(&chains[chains.len().saturating_sub(count)..]),
+        Retention::Age(age) => {
+            let cutoff = now_millis().saturating_sub(age.as_millis() as u64);
+            //a chain was current until the first record of the next one, a trailing removal until now
+            let mut first = chains.len();
+            for (i, next) in chains.iter().skip(1).map(Some).chain(std::iter::once(current)).enumerate() {
+                let until = match next {
+                    Some(next) => records.log(next.start(), next.end())?.4,
+                    None => records.log(chains[i].start(), chains[i].end())?.4,
+                };
+                if until >= cutoff {
+                    first = i;
+                    break;
+                }
+            }
+            Ok(&chains[first..])
+        }
+    }
+}
 
-///it points to where data is stored in disk
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct Index {
-    key: String,
-    start: usize,
-    end: usize,
+///append the records of a chain unchanged and return where they are now
+fn copy_chain(records: &mut Records, writer: &mut Writer, index: &Index) -> Result<Index> {
+    let (start, len) = append_serialized(writer, records.raw(index.start(), index.end())?)?;
+    let mut copied = Index::new(start, len, index.version);
+    for (operand_start, operand_end) in index.operands() {
+        let (start, len) = append_serialized(writer, records.raw(operand_start, operand_end)?)?;
+        copied.push_operand(start, len);
+    }
+    Ok(copied)
 }
 
-///data stored in disk,log(key,value)
+///(key,length,kind,sequence)->(key,index,kind)
+///data stored in disk,log(key,value,merge operand,sequence number,milliseconds since the unix epoch)
 #[derive(Debug, Serialize, Deserialize)]
-struct Log(String, Option<String>);
+pub(crate) struct Log(
+    #[serde(with = "crate::bytes")] pub(crate) Vec<u8>,
+    #[serde(with = "crate::bytes::option")] pub(crate) Option<Vec<u8>>,
+    #[serde(with = "crate::bytes::option", default)] pub(crate) Option<Vec<u8>>,
+    #[serde(default)] pub(crate) u64,
+    #[serde(default)] pub(crate) u64,
+);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum LogKind {
+    Set,
+    Remove,
+    Merge,
+}
+
+impl Log {
+    fn into_event(self) -> Event {
+        match (self.1, self.2) {
+            (Some(value), _) => Event::Set { sequence: self.3, key: self.0, value },
+            (None, Some(operand)) => Event::Merge { sequence: self.3, key: self.0, operand },
+            (None, None) => Event::Remove { sequence: self.3, key: self.0 },
+        }
+    }
+
+    pub(crate) fn kind(&self) -> LogKind {
+        match (&self.1, &self.2) {
+            (Some(_), _) => LogKind::Set,
+            (None, Some(_)) => LogKind::Merge,
+            (None, None) => LogKind::Remove,
+        }
+    }
+}
+
+///read-only view of a `Database`, it keeps the index of the moment it was taken
+struct DatabaseSnapshot {
+    sequence: u64,
+    index: Arc<Keydir>,
+    records: Records,
+    cache: Option<Arc<ValueCache>>,
+    generation: u64,
+    merge_operator: Option<MergeOperator>,
+    _pin: Arc<()>,
+}
+
+impl Snapshot for DatabaseSnapshot {
+    fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match find(&self.index, &mut self.records, &key)? {
+            None => Ok(None),
+            Some(index) => read_value(&mut self.records, self.cache.as_deref(), self.generation, index, self.merge_operator.as_ref()),
+        }
+    }
+
+    ///a scan goes around the cache so that it does not evict the hot values. when only hashes of the
+    ///keys are kept, every key is read back from its record and sorted before the first pair comes out
+    fn iter(&mut self) -> Pairs<'_> {
+        pairs(&self.index, &mut self.records, self.merge_operator.as_ref())
+    }
+}
+
+///the live pairs of `index` in key order, each value folded when it comes out
+fn pairs<'a>(index: &'a Keydir, records: &'a mut Records, merge_operator: Option<&'a MergeOperator>) -> Pairs<'a> {
+    let mut entries = vec![];
+    for (key, index) in index.entries() {
+        let key = match key {
+            Some(key) => key.to_vec(),
+            None => match records.log(index.start(), index.end()) {
+                Ok(log) => log.0,
+                Err(e) => return Box::new(std::iter::once(Err(e))),
+            },
+        };
+        entries.push((key, index));
+    }
+    if matches!(index, Keydir::Hashed(..)) {
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+    }
+    Box::new(entries.into_iter().filter_map(move |(key, index)| {
+        match fold(records, index, merge_operator) {
+            Ok(None) => None,
+            Ok(Some(value)) => Some(Ok((key, value))),
+            Err(e) => Some(Err(e)),
+        }
+    }))
+}
 
 #[cfg(test)]
 mod tests {
     use tempfile::TempDir;
     use crate::err::{Result, Error};
     use crate::{KvsEngine, KvStore};
-    use crate::kvs::database::{Index, Log};
-    use std::path::Path;
+    use crate::kvs::database::{Log, CompactionStep, COMPACTION_PREFIX};
+    use crate::kvs::{MergeOperator, Options, CompactionPolicy, MemoryVfs, Vfs, Transaction, run_transaction, Retention, At, Version, Event};
+    use std::time::Duration;
+    use std::sync::{Arc, Mutex};
+    use std::io::Write;
+    use crate::kvs::ProgressFn;
 
     #[test]
-    fn test_open() -> Result<()> {
+    fn test_set() -> Result<()> {
         let tmp = TempDir::new().expect("create new dir err");
-        let db = KvStore::open(tmp.path())?;
-        drop(db);
+        let mut db = KvStore::open(tmp.path())?;
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        let stored: Log = serde_json::from_slice(&std::fs::read(tmp.path().join(".data"))?)?;
+        let content = Log(b"key1".to_vec(), Some(b"value1".to_vec()), None, 1, stored.4);
+        let len = serde_json::to_string(&content)?.len();
+        let stored_data = db.index.get(b"key1".as_ref()).cloned().unwrap();
+
+        //the key is only kept by the keydir
+        assert_eq!(db.index.len(), 1);
+        assert_eq!(stored_data.start(), 0);
+        assert_eq!(stored_data.end(), len);
+
         Ok(())
     }
 
     #[test]
-    fn test_set() -> Result<()> {
+    fn test_compaction() -> Result<()> {
         let tmp = TempDir::new().expect("create new dir err");
         let mut db = KvStore::open(tmp.path())?;
-        db.set("key1".to_owned(), "value1".to_owned());
-        let content = Log("key1".to_owned(), Some("value1".to_owned()));
-        let len = serde_json::to_string(&content)?.len();
-        let stored_data = db.index.get("key1").cloned().unwrap();
 
-        assert_eq!(stored_data.key, "key1".to_owned());
-        assert_eq!(stored_data.start, 0);
-        assert_eq!(stored_data.end, len);
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        db.set("key1".to_owned(), "value2".to_owned())?;
+
+        db.set("key2".to_owned(), "value2".to_owned())?;
+        db.remove("key2".to_owned())?;
+
+        db.compact()?;
+        assert_eq!(db.index.len(), 1);
+        assert_eq!(db.get("key1".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(db.get("key2".to_owned())?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge() -> Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
+        let mut db = KvStore::open(tmp.path())?;
+        assert!(db.merge("key1".to_owned(), "a".to_owned()).is_err());
+        drop(db);
+
+        let options = || Options { merge_operator: Some(MergeOperator::Append), ..Options::default() };
+        let mut db = KvStore::open_with(tmp.path(), options())?;
+        db.merge("key1".to_owned(), "a".to_owned())?;
+        db.merge("key1".to_owned(), "b".to_owned())?;
+        db.set("key2".to_owned(), "x".to_owned())?;
+        db.merge("key2".to_owned(), "y".to_owned())?;
+        assert_eq!(db.get("key1".to_owned())?, Some("ab".to_owned()));
+        assert_eq!(db.get("key2".to_owned())?, Some("xy".to_owned()));
+
+        //operands are replayed after reopen and folded by compaction
+        drop(db);
+        let mut db = KvStore::open_with(tmp.path(), options())?;
+        assert_eq!(db.get("key1".to_owned())?, Some("ab".to_owned()));
+        db.compact()?;
+        assert!(db.index.values().all(|index| !index.has_operands()));
+        assert_eq!(db.get("key1".to_owned())?, Some("ab".to_owned()));
+        assert_eq!(db.get("key2".to_owned())?, Some("xy".to_owned()));
+
+        //overwriting the key drops pending operands
+        db.merge("key2".to_owned(), "z".to_owned())?;
+        db.set("key2".to_owned(), "w".to_owned())?;
+        assert_eq!(db.get("key2".to_owned())?, Some("w".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_merge_operator() -> Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
+        let sum = MergeOperator::custom(|_, old, operand| {
+            let parse = |v: &[u8]| String::from_utf8(v.to_vec()).unwrap().parse::<i64>().unwrap();
+            Some((old.map(parse).unwrap_or(0) + parse(operand)).to_string().into_bytes())
+        });
+        let mut db = KvStore::open_with(tmp.path(), Options { merge_operator: Some(sum), ..Options::default() })?;
+        db.merge("key1".to_owned(), "3".to_owned())?;
+        db.merge("key1".to_owned(), "4".to_owned())?;
+        assert_eq!(db.get("key1".to_owned())?, Some("7".to_owned()));
+
+        let mut db = KvStore::open_with(tmp.path(), Options { merge_operator: Some(MergeOperator::Max), ..Options::default() })?;
+        db.merge("key1".to_owned(), "10".to_owned())?;
+        db.merge("key1".to_owned(), "9".to_owned())?;
+        assert_eq!(db.get("key1".to_owned())?, Some("10".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_representation() -> Result<()> {
+        //text is kept as a JSON string so logs written before bytes keys still parse
+        let log: Log = serde_json::from_str(r#"["key1","value1"]"#)?;
+        assert_eq!(log.0, b"key1".to_vec());
+        assert_eq!(serde_json::to_string(&log)?, r#"["key1","value1",null,0,0]"#);
 
+        let log = Log(vec![0xff], None, Some(vec![0xfe, b'a']), 7, 1600000000000);
+        assert_eq!(serde_json::to_string(&log)?, r#"[{"b64":"/w=="},null,{"b64":"/mE="},7,1600000000000]"#);
+        //binary data written as arrays of numbers still parses
+        let old: Log = serde_json::from_str(r#"[[255],null,[254,97],7,1600000000000]"#)?;
+        assert_eq!((old.0, old.2), (log.0, log.2));
+
+        //and takes about a third more room than the bytes
+        let value: Vec<u8> = (0..1000).map(|i| (i * 7 % 256) as u8).collect();
+        let log = Log(b"key1".to_vec(), Some(value.clone()), None, 7, 1600000000000);
+        let serialized = serde_json::to_string(&log)?;
+        assert!(serialized.len() < value.len() * 4 / 3 + 64);
+        assert_eq!(serde_json::from_str::<Log>(&serialized)?.1, Some(value));
         Ok(())
     }
 
     #[test]
-    fn test_get() -> Result<()> {
+    fn test_snapshot() -> Result<()> {
         let tmp = TempDir::new().expect("create new dir err");
         let mut db = KvStore::open(tmp.path())?;
         db.set("key1".to_owned(), "value1".to_owned())?;
+        db.set("key2".to_owned(), "value2".to_owned())?;
+        let mut snapshot = db.snapshot()?;
+        assert_eq!(snapshot.sequence(), 2);
+
+        db.set("key1".to_owned(), "value3".to_owned())?;
+        db.remove("key2".to_owned())?;
+        db.set("key3".to_owned(), "value3".to_owned())?;
+        assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(snapshot.get("key3".to_owned())?, None);
+        let pairs = snapshot.iter().collect::<Result<Vec<_>>>()?;
+        assert_eq!(pairs, vec![(b"key1".to_vec(), b"value1".to_vec()), (b"key2".to_vec(), b"value2".to_vec())]);
+
+        //automatic compaction is held back while the snapshot pins the data file
+        db.outdated_len = db.compaction.dead_bytes;
+        db.maybe_compact()?;
+        assert!(db.outdated_len > 0);
+        assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+        drop(snapshot);
+        db.maybe_compact()?;
+        assert_eq!(db.outdated_len, 0);
+        assert_eq!(db.get("key1".to_owned())?, Some("value3".to_owned()));
+
+        drop(db);
+        let mut db = KvStore::open(tmp.path())?;
+        assert_eq!(db.snapshot()?.sequence(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mmap() -> Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
+        let options = Options { mmap: true, merge_operator: Some(MergeOperator::Append), ..Options::default() };
+        let mut db = KvStore::open_with(tmp.path(), options.clone())?;
+        assert_eq!(db.get("key1".to_owned())?, None);
+        //records appended after the file was mapped are read through a new map
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(db.get("key1".to_owned())?, Some("value1".to_owned()));
+        db.set("key2".to_owned(), "value2".to_owned())?;
+        db.merge("key4".to_owned(), "a".to_owned())?;
+        db.merge("key4".to_owned(), "b".to_owned())?;
+        let mut snapshot = db.snapshot()?;
+
+        //a snapshot keeps reading the file it was taken on once compaction replaced it
+        db.set("key1".to_owned(), "value3".to_owned())?;
+        db.compact()?;
+        assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(db.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(db.get("key4".to_owned())?, Some("ab".to_owned()));
+        assert!(db.check_index()?.is_empty());
+        drop(snapshot);
+
+        drop(db);
+        let mut db = KvStore::open_with(tmp.path(), options.clone())?;
+        assert_eq!(db.get("key1".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(db.get("key4".to_owned())?, Some("ab".to_owned()));
+
+        //a file system other than the local disk cannot be mapped
+        let vfs: Arc<dyn Vfs> = Arc::new(MemoryVfs::new());
+        assert!(matches!(KvStore::open_with("/db", Options { vfs: Some(vfs), ..options }), Err(Error::MmapUnavailableError)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache() -> Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
+        let options = Options { cache_bytes: 1 << 20, merge_operator: Some(MergeOperator::Append), ..Options::default() };
+        let mut db = KvStore::open_with(tmp.path(), options)?;
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(db.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(db.get("key1".to_owned())?, Some("value1".to_owned()));
+        let stats = db.stats()?;
+        assert_eq!((stats.cache_hits, stats.cache_misses), (1, 1));
+
+        //writes supersede the cached value
+        let mut snapshot = db.snapshot()?;
         db.set("key1".to_owned(), "value2".to_owned())?;
         assert_eq!(db.get("key1".to_owned())?, Some("value2".to_owned()));
+        db.merge("key1".to_owned(), "a".to_owned())?;
+        assert_eq!(db.get("key1".to_owned())?, Some("value2a".to_owned()));
+        assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+        db.remove("key1".to_owned())?;
+        assert_eq!(db.get("key1".to_owned())?, None);
 
+        //compaction moves the records, the snapshot keeps reading the old data file
+        db.set("key2".to_owned(), "value3".to_owned())?;
+        assert_eq!(db.get("key2".to_owned())?, Some("value3".to_owned()));
+        db.compact()?;
+        db.set("key3".to_owned(), "value4".to_owned())?;
+        assert_eq!(db.get("key2".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(db.get("key3".to_owned())?, Some("value4".to_owned()));
+        assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(snapshot.get("key2".to_owned())?, None);
+        assert_eq!(db.get("key2".to_owned())?, Some("value3".to_owned()));
+        let stats = db.stats()?;
+        assert_eq!((stats.cache_hits, stats.cache_misses), (2, 8));
+        Ok(())
+    }
 
+    #[test]
+    fn test_hashed_keys() -> Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
+        let options = Options { hashed_keys: true, merge_operator: Some(MergeOperator::Append), ..Options::default() };
+        let mut db = KvStore::open_with(tmp.path(), options.clone())?;
+        for i in [3, 1, 4, 5, 9, 2, 6] {
+            db.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        db.remove("key4".to_owned())?;
+        db.merge("key1".to_owned(), "a".to_owned())?;
+        db.compact()?;
+        db.merge("key2".to_owned(), "b".to_owned())?;
+        assert!(db.check_index()?.is_empty());
         drop(db);
+
+        //scans read the keys back from the records and sort them
+        let mut db = KvStore::open_with(tmp.path(), options)?;
+        let pairs = db.snapshot()?.iter().collect::<Result<Vec<_>>>()?;
+        let keys: Vec<_> = pairs.iter().map(|(key, _)| String::from_utf8_lossy(key).into_owned()).collect();
+        assert_eq!(keys, vec!["key1", "key2", "key3", "key5", "key6", "key9"]);
+        assert_eq!(pairs[0].1, b"value1a".to_vec());
+        assert_eq!(db.get("key2".to_owned())?, Some("value2b".to_owned()));
+        assert_eq!(db.get("key4".to_owned())?, None);
+
+        //a key whose hash leads to the record of another key is absent and cannot be written
+        let index = db.index.get(b"key1".as_ref()).cloned().unwrap();
+        Arc::make_mut(&mut db.index).insert(b"other".to_vec(), index);
+        assert_eq!(db.get("other".to_owned())?, None);
+        assert!(matches!(db.set("other".to_owned(), "value".to_owned()), Err(Error::KeyCollisionError)));
+        assert!(matches!(db.merge("other".to_owned(), "c".to_owned()), Err(Error::KeyCollisionError)));
+        assert!(matches!(db.remove("other".to_owned()), Err(Error::KeyNotFoundError)));
+        assert_eq!(db.get("key1".to_owned())?, Some("value1a".to_owned()));
+
+        //with long keys the hashes take less memory than the keys themselves
+        let long_key = |i: usize| format!("{:0>200}", i);
+        let (hashed_tmp, ordered_tmp) = (TempDir::new().expect("create new dir err"), TempDir::new().expect("create new dir err"));
+        let mut hashed = KvStore::open_with(hashed_tmp.path(), Options { hashed_keys: true, ..Options::default() })?;
+        let mut ordered = KvStore::open(ordered_tmp.path())?;
+        for i in 0..100 {
+            hashed.set(long_key(i), "value".to_owned())?;
+            ordered.set(long_key(i), "value".to_owned())?;
+        }
+        assert!(hashed.stats()?.index_bytes < ordered.stats()?.index_bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_recovery() -> Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
+        let compaction = CompactionPolicy { disabled: true, ..CompactionPolicy::default() };
+        let options = Options { compaction, merge_operator: Some(MergeOperator::Append), ..Options::default() };
+        let mut db = KvStore::open_with(tmp.path(), options.clone())?;
+        //far more records than a recursive replay could take
+        for i in 0..100_000 {
+            db.set(format!("key{}", i % 1000), format!("value{}", i))?;
+        }
+        db.remove("key0".to_owned())?;
+        db.merge("key1".to_owned(), "a".to_owned())?;
+        let usage = db.usage();
+        drop(db);
+
+        let reports = Arc::new(Mutex::new(vec![]));
+        let sink = reports.clone();
+        let progress: Arc<ProgressFn> = Arc::new(move |replayed, total, torn| sink.lock().unwrap().push((replayed, total, torn)));
+        let mut db = KvStore::open_with(tmp.path(), Options { recovery_progress: Some(progress), ..options.clone() })?;
+        assert_eq!(db.usage(), usage);
+        assert_eq!(db.get("key1".to_owned())?, Some("value99001a".to_owned()));
+        assert!(db.check_index()?.is_empty());
+        let stats = db.stats()?;
+        assert_eq!(stats.recovered_records, 100_002);
+        assert!(stats.recovery.is_some());
+        let len = std::fs::metadata(tmp.path().join(".data"))?.len();
+        assert_eq!(reports.lock().unwrap().last(), Some(&(len, len, 0)));
+        drop(db);
+
+        //a record torn by a crash during its append is cut off
+        let append = |bytes: &[u8]| -> Result<()> {
+            let mut data = std::fs::OpenOptions::new().append(true).open(tmp.path().join(".data"))?;
+            data.write_all(bytes)?;
+            Ok(())
+        };
+        append(b"[\"key2\",\"val")?;
+        let sink = reports.clone();
+        let progress: Arc<ProgressFn> = Arc::new(move |replayed, total, torn| sink.lock().unwrap().push((replayed, total, torn)));
+        let mut db = KvStore::open_with(tmp.path(), Options { recovery_progress: Some(progress), ..options.clone() })?;
+        assert_eq!(reports.lock().unwrap().last(), Some(&(len, len + 12, 12)));
+        assert_eq!(std::fs::metadata(tmp.path().join(".data"))?.len(), len);
+        db.set("key2".to_owned(), "value".to_owned())?;
+        drop(db);
+        let mut db = KvStore::open_with(tmp.path(), options)?;
+        assert_eq!(db.get("key2".to_owned())?, Some("value".to_owned()));
+        assert_eq!(db.stats()?.recovered_records, 100_003);
+        drop(db);
+
+        //a record that does not parse before the end fails the open instead of being skipped
+        append(b"}[\"key\",null]")?;
+        assert!(KvStore::open(tmp.path()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_transaction() -> Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
         let mut db = KvStore::open(tmp.path())?;
-        let res = db.get("key1".to_owned())?;
-        assert_eq!(db.get("key1".to_owned())?, Some("value2".to_owned()));
+        db.set("alice".to_owned(), "100".to_owned())?;
+        db.set("bob".to_owned(), "0".to_owned())?;
+
+        let mut txn = Transaction::new();
+        let alice = txn.get(&mut db, b"alice".to_vec())?.unwrap();
+        assert_eq!(txn.get(&mut db, b"bob".to_vec())?, Some(b"0".to_vec()));
+        assert_eq!(alice, b"100".to_vec());
+        txn.set(b"alice".to_vec(), b"70".to_vec());
+        txn.set(b"bob".to_vec(), b"30".to_vec());
+        txn.remove(b"carol".to_vec());
+        assert_eq!(txn.get(&mut db, b"alice".to_vec())?, Some(b"70".to_vec()));
+        db.commit(txn)?;
+        assert_eq!(db.get("alice".to_owned())?, Some("70".to_owned()));
+        assert_eq!(db.get("bob".to_owned())?, Some("30".to_owned()));
+
+        //a write between the read and the commit aborts the transaction
+        let mut txn = Transaction::new();
+        txn.get(&mut db, b"alice".to_vec())?;
+        txn.set(b"bob".to_vec(), b"0".to_vec());
+        db.set("alice".to_owned(), "50".to_owned())?;
+        assert!(matches!(db.commit(txn), Err(Error::TransactionConflictError)));
+        assert_eq!(db.get("bob".to_owned())?, Some("30".to_owned()));
+
+        //versions survive reopen
+        let mut txn = Transaction::new();
+        txn.get(&mut db, b"alice".to_vec())?;
+        drop(db);
+        let mut db = KvStore::open(tmp.path())?;
+        txn.set(b"alice".to_vec(), b"0".to_vec());
+        db.commit(txn)?;
+        assert_eq!(db.get("alice".to_owned())?, Some("0".to_owned()));
         Ok(())
     }
 
     #[test]
-    fn test_remove() -> Result<()> {
+    fn test_run_transaction_retries() -> Result<()> {
         let tmp = TempDir::new().expect("create new dir err");
         let mut db = KvStore::open(tmp.path())?;
+        db.set("counter".to_owned(), "1".to_owned())?;
+        let mut attempts = 0;
+        let value = run_transaction(&mut db, 3, |txn, db| {
+            attempts += 1;
+            let value = txn.get(db, b"counter".to_vec())?.unwrap();
+            if attempts == 1 {
+                //another writer gets in first
+                db.set("counter".to_owned(), "5".to_owned())?;
+            }
+            let value = String::from_utf8(value)?.parse::<i64>().unwrap() * 2;
+            txn.set(b"counter".to_vec(), value.to_string().into_bytes());
+            Ok(value)
+        })?;
+        assert_eq!(attempts, 2);
+        assert_eq!(value, 10);
+        assert_eq!(db.get("counter".to_owned())?, Some("10".to_owned()));
+
+        let res = run_transaction(&mut db, 0, |txn, db| {
+            txn.get(db, b"counter".to_vec())?;
+            db.set("counter".to_owned(), "0".to_owned())
+        });
+        assert!(matches!(res, Err(Error::TransactionConflictError)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_history() -> Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
+        let options = || Options { retention: Some(Retention::Versions(2)), ..Options::default() };
+        let mut db = KvStore::open_with(tmp.path(), options())?;
         db.set("key1".to_owned(), "value1".to_owned())?;
+        db.set("key1".to_owned(), "value2".to_owned())?;
         db.remove("key1".to_owned())?;
+        db.set("key1".to_owned(), "value3".to_owned())?;
 
-        assert_eq!(db.get("key1".to_owned())?, None);
-        assert!(db.remove("key1".to_owned()).is_err());
-        assert!(db.remove("key2".to_owned()).is_err());
+        let values = |history: Vec<Version>| history.into_iter()
+            .map(|version| (version.sequence, version.value))
+            .collect::<Vec<_>>();
+        assert_eq!(values(db.history("key1".to_owned())?), vec![
+            (1, Some(b"value1".to_vec())),
+            (2, Some(b"value2".to_vec())),
+            (3, None),
+            (4, Some(b"value3".to_vec())),
+        ]);
+        assert_eq!(db.get_at("key1".to_owned(), At::Sequence(0))?, None);
+        assert_eq!(db.get_at("key1".to_owned(), At::Sequence(2))?, Some("value2".to_owned()));
+        assert_eq!(db.get_at("key1".to_owned(), At::Sequence(3))?, None);
+        assert_eq!(db.get_at("key1".to_owned(), At::Sequence(9))?, Some("value3".to_owned()));
+        let timestamp = db.history("key1".to_owned())?[1].timestamp;
+        assert!(db.get_at("key1".to_owned(), At::Timestamp(timestamp))?.is_some());
+
+        //compaction keeps the two latest prior versions, and they survive a reopen
+        db.compact()?;
+        assert_eq!(values(db.history("key1".to_owned())?), vec![
+            (2, Some(b"value2".to_vec())),
+            (3, None),
+            (4, Some(b"value3".to_vec())),
+        ]);
+        drop(db);
+        let mut db = KvStore::open_with(tmp.path(), options())?;
+        assert_eq!(db.history("key1".to_owned())?.len(), 3);
+        assert_eq!(db.get_at("key1".to_owned(), At::Sequence(1))?, None);
+        assert_eq!(db.get_at("key1".to_owned(), At::Sequence(2))?, Some("value2".to_owned()));
+
+        //without a retention only the latest version is known
         drop(db);
         let mut db = KvStore::open(tmp.path())?;
-        assert!(db.remove("key1".to_owned()).is_err());
+        assert_eq!(values(db.history("key1".to_owned())?), vec![(4, Some(b"value3".to_vec()))]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_history_retention_age() -> Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
+        let options = Options {
+            retention: Some(Retention::Age(Duration::from_secs(3600))),
+            merge_operator: Some(MergeOperator::Append),
+            ..Options::default()
+        };
+        let mut db = KvStore::open_with(tmp.path(), options)?;
+        db.set("key1".to_owned(), "a".to_owned())?;
+        db.merge("key1".to_owned(), "b".to_owned())?;
+        db.set("key1".to_owned(), "c".to_owned())?;
+        db.set("key2".to_owned(), "x".to_owned())?;
+        db.remove("key2".to_owned())?;
+
+        //every version is within the hour, merge operands are versions of their own
+        db.compact()?;
+        let history = db.history("key1".to_owned())?;
+        let values: Vec<_> = history.into_iter().map(|version| version.value).collect();
+        assert_eq!(values, vec![Some(b"a".to_vec()), Some(b"ab".to_vec()), Some(b"c".to_vec())]);
+        assert_eq!(db.get_at("key2".to_owned(), At::Sequence(4))?, Some("x".to_owned()));
+        assert_eq!(db.get_at("key2".to_owned(), At::Sequence(5))?, None);
 
+        //versions superseded longer ago than the retention age are pruned
+        db.retention = Some(Retention::Age(Duration::from_secs(0)));
+        std::thread::sleep(Duration::from_millis(5));
+        db.compact()?;
+        assert_eq!(db.history("key1".to_owned())?.len(), 1);
+        assert!(db.history("key2".to_owned())?.is_empty());
         Ok(())
     }
 
     #[test]
-    fn test_compaction() -> Result<()> {
+    fn test_watch() -> Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
+        let options = Options { merge_operator: Some(MergeOperator::Append), ..Options::default() };
+        let mut db = KvStore::open_with(tmp.path(), options)?;
+        let mut watch = db.watch(b"app.".to_vec())?;
+        let mut key_watch = db.watch(b"app.name".to_vec())?;
+        db.set("app.name".to_owned(), "kvs".to_owned())?;
+        db.set("other".to_owned(), "value".to_owned())?;
+        db.merge("app.name".to_owned(), "-server".to_owned())?;
+        db.remove("app.name".to_owned())?;
+        let mut txn = Transaction::new();
+        txn.set(b"app.port".to_vec(), b"4000".to_vec());
+        db.commit(txn)?;
+
+        let set = |sequence, key: &str, value: &str| Event::Set { sequence, key: key.into(), value: value.into() };
+        assert_eq!(watch.next(), Some(set(1, "app.name", "kvs")));
+        assert_eq!(watch.next(), Some(set(3, "app.name", "kvs-server")));
+        assert_eq!(watch.next(), Some(Event::Remove { sequence: 4, key: b"app.name".to_vec() }));
+        assert_eq!(watch.next(), Some(set(5, "app.port", "4000")));
+        assert_eq!(key_watch.next().map(|event| event.sequence()), Some(1));
+
+        //dropping the engine ends the watch
+        drop(db);
+        assert_eq!(watch.next(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_changes() -> Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
+        let options = || Options {
+            merge_operator: Some(MergeOperator::Append),
+            retained_segments: 1,
+            ..Options::default()
+        };
+        let mut db = KvStore::open_with(tmp.path(), options())?;
+        db.set("key1".to_owned(), "a".to_owned())?;
+        db.set("key1".to_owned(), "b".to_owned())?;
+        db.remove("key1".to_owned())?;
+        db.set("key2".to_owned(), "x".to_owned())?;
+        db.compact()?;
+        db.merge("key2".to_owned(), "y".to_owned())?;
+
+        //the first log is retained as a segment, the records compaction copied are not repeated
+        let mut changes = db.changes(0)?;
+        let stored: Vec<Event> = changes.by_ref().take(5).collect::<Result<_>>()?;
+        assert_eq!(stored, vec![
+            Event::Set { sequence: 1, key: b"key1".to_vec(), value: b"a".to_vec() },
+            Event::Set { sequence: 2, key: b"key1".to_vec(), value: b"b".to_vec() },
+            Event::Remove { sequence: 3, key: b"key1".to_vec() },
+            Event::Set { sequence: 4, key: b"key2".to_vec(), value: b"x".to_vec() },
+            Event::Merge { sequence: 5, key: b"key2".to_vec(), operand: b"y".to_vec() },
+        ]);
+        //then the feed follows the writes
+        db.set("key3".to_owned(), "z".to_owned())?;
+        assert_eq!(changes.next().unwrap()?.sequence(), 6);
+
+        //a second compaction replaces the oldest segment, so sequence numbers before it are gone
+        db.compact()?;
+        assert!(matches!(db.changes(0), Err(Error::SequenceUnavailableError)));
+        let sequences = |changes: crate::Changes, n| changes.take(n).map(|event| event.map(|event| event.sequence())).collect::<Result<Vec<_>>>();
+        assert_eq!(sequences(db.changes(4)?, 2)?, vec![5, 6]);
+
+        drop(changes);
+        drop(db);
+        let mut db = KvStore::open_with(tmp.path(), options())?;
+        assert!(matches!(db.changes(3), Err(Error::SequenceUnavailableError)));
+        let changes = db.changes(5)?;
+        db.remove("key3".to_owned())?;
+        assert_eq!(sequences(changes, 2)?, vec![6, 7]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sequence_after_compaction() -> Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
+        let mut db = KvStore::open(tmp.path())?;
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        db.set("key2".to_owned(), "value2".to_owned())?;
+        db.remove("key2".to_owned())?;
+        let last_seen = db.sequence;
+        //compaction drops the removal, the latest record left has sequence number 1
+        db.compact()?;
+        drop(db);
+
+        let mut db = KvStore::open(tmp.path())?;
+        db.set("key3".to_owned(), "value3".to_owned())?;
+        assert_eq!(db.sequence, last_seen + 1);
+        let events: Vec<Event> = db.changes(last_seen)?.take(1).collect::<Result<_>>()?;
+        assert_eq!(events, vec![Event::Set { sequence: last_seen + 1, key: b"key3".to_vec(), value: b"value3".to_vec() }]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup() -> Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
+        let options = || Options { retained_segments: 1, ..Options::default() };
+        let mut db = KvStore::open_with(tmp.path(), options())?;
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        db.set("key1".to_owned(), "value2".to_owned())?;
+        db.compact()?;
+        db.set("key2".to_owned(), "value3".to_owned())?;
+
+        let backup = TempDir::new().expect("create new dir err");
+        db.backup(backup.path())?;
+        db.set("key3".to_owned(), "value4".to_owned())?;
+        assert!(matches!(db.backup(backup.path()), Err(Error::DirectoryNotEmptyError)));
+
+        let restored = TempDir::new().expect("create new dir err");
+        crate::restore(backup.path(), &restored.path().join("data"))?;
+        for dir in [backup.path().to_path_buf(), restored.path().join("data")] {
+            let mut copy = KvStore::open_with(dir, options())?;
+            assert_eq!(copy.get("key1".to_owned())?, Some("value2".to_owned()));
+            assert_eq!(copy.get("key2".to_owned())?, Some("value3".to_owned()));
+            assert_eq!(copy.get("key3".to_owned())?, None);
+            //the retained segment came along, so the change feed still starts at the beginning
+            assert_eq!(copy.changes(0)?.next().unwrap()?.sequence(), 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_inspect() -> Result<()> {
+        use crate::kvs::inspect::{self, Entry, RecordKind};
+        let tmp = TempDir::new().expect("create new dir err");
+        let mut db = KvStore::open(tmp.path())?;
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        db.set("key1".to_owned(), "value2".to_owned())?;
+        db.remove("key1".to_owned())?;
+        db.set("key2".to_owned(), "value3".to_owned())?;
+        drop(db);
+
+        let entries = inspect::dump(tmp.path())?;
+        assert_eq!(entries.len(), 4);
+        assert!(matches!(&entries[2], Entry::Record { sequence: 3, kind: RecordKind::Remove, key, .. } if key == b"key1"));
+        assert!(inspect::verify(tmp.path())?.is_empty());
+        let stats = inspect::stats(tmp.path())?;
+        assert_eq!((stats.records, stats.keys, stats.corrupt_bytes), (4, 1, 0));
+        let len = std::fs::metadata(tmp.path().join(".data"))?.len() as usize;
+        assert_eq!(stats.live_bytes + stats.dead_bytes, len);
+
+        //garbage between two records is reported, repair drops it and keeps the rest
+        let offset = match entries[3] {
+            Entry::Record { offset, .. } => offset,
+            _ => unreachable!(),
+        };
+        let mut bytes = std::fs::read(tmp.path().join(".data"))?;
+        bytes.splice(offset..offset, b"[\"broken".iter().cloned());
+        std::fs::write(tmp.path().join(".data"), &bytes)?;
+        assert!(KvStore::open(tmp.path()).is_err());
+        assert_eq!(inspect::verify(tmp.path())?.len(), 1);
+        assert_eq!(inspect::dump(tmp.path())?[3], Entry::Corrupt { offset, len: 8 });
+
+        //inspecting leaves the files as they are, a torn tail and a stale compaction file included
+        let stale = tmp.path().join(format!("{}.1.1", COMPACTION_PREFIX));
+        std::fs::write(&stale, b"")?;
+        let mut torn = bytes.clone();
+        torn.extend_from_slice(b"[\"key3\",\"val");
+        std::fs::write(tmp.path().join(".data"), &torn)?;
+        assert_eq!(inspect::verify(tmp.path())?.len(), 2);
+        assert_eq!(inspect::stats(tmp.path())?.corrupt_bytes, 8 + 12);
+        assert_eq!(std::fs::read(tmp.path().join(".data"))?, torn);
+        assert!(stale.is_file());
+        std::fs::remove_file(&stale)?;
+        std::fs::write(tmp.path().join(".data"), &bytes)?;
+
+        let report = inspect::repair(tmp.path())?;
+        assert_eq!((report.kept, report.dropped_bytes), (4, 8));
+        assert_eq!(report.corrupt, tmp.path().join(".data.corrupt"));
+        assert!(tmp.path().join(".data.corrupt").is_file());
+        assert!(inspect::verify(tmp.path())?.is_empty());
+        let mut db = KvStore::open(tmp.path())?;
+        assert_eq!(db.get("key2".to_owned())?, Some("value3".to_owned()));
+        drop(db);
+
+        //a second repair keeps the log of the first one
+        let first = std::fs::read(tmp.path().join(".data.corrupt"))?;
+        let report = inspect::repair(tmp.path())?;
+        assert_eq!(report.corrupt, tmp.path().join(".data.corrupt.1"));
+        assert_eq!(std::fs::read(tmp.path().join(".data.corrupt"))?, first);
+        assert!(tmp.path().join(".data.corrupt.1").is_file());
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() -> Result<()> {
         let tmp = TempDir::new().expect("create new dir err");
         let mut db = KvStore::open(tmp.path())?;
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        db.set("key1".to_owned(), "value2".to_owned())?;
+        db.set("key2".to_owned(), "value3".to_owned())?;
+        db.get("key1".to_owned())?;
+        let stats = db.stats()?;
+        assert_eq!((stats.keys, stats.reads, stats.writes), (2, 1, 3));
+        assert_eq!((stats.segments, stats.compactions, stats.last_compaction), (1, 0, None));
+        let len = std::fs::metadata(tmp.path().join(".data"))?.len();
+        assert_eq!(stats.live_bytes + stats.dead_bytes, len);
+        assert!(stats.dead_bytes > 0);
+
+        db.compact()?;
+        let stats = db.stats()?;
+        assert_eq!((stats.dead_bytes, stats.compactions), (0, 1));
+        assert!(stats.last_compaction.is_some());
+        Ok(())
+    }
 
-        db.set("key1".to_owned(), "value1".to_owned());
-        db.set("key1".to_owned(), "value2".to_owned());
+    #[test]
+    fn test_compaction_policy() -> Result<()> {
+        let policy = CompactionPolicy { dead_bytes: 10, dead_ratio: 0.5, window: Some((22, 4)), disabled: false };
+        assert!(policy.should_compact_at(10, 20, 23));
+        assert!(policy.should_compact_at(10, 20, 3));
+        assert!(!policy.should_compact_at(10, 20, 4));
+        assert!(!policy.should_compact_at(9, 0, 23));
+        assert!(!policy.should_compact_at(10, 21, 23));
+        assert!(!CompactionPolicy { disabled: true, ..policy.clone() }.should_compact_at(10, 20, 23));
+        assert!(CompactionPolicy { window: Some((5, 5)), ..policy }.should_compact_at(10, 20, 12));
 
-        db.set("key2".to_owned(), "value2".to_owned());
-        db.remove("key2".to_owned());
+        //a low threshold compacts after the write that crosses it
+        let tmp = TempDir::new().expect("create new dir err");
+        let compaction = CompactionPolicy { dead_bytes: 1, ..CompactionPolicy::default() };
+        let mut db = KvStore::open_with(tmp.path(), Options { compaction, ..Options::default() })?;
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        db.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!((db.outdated_len, db.compactions), (0, 1));
 
+        //a disabled policy leaves it to `compact`
+        let tmp = TempDir::new().expect("create new dir err");
+        let compaction = CompactionPolicy { dead_bytes: 1, disabled: true, ..CompactionPolicy::default() };
+        let mut db = KvStore::open_with(tmp.path(), Options { compaction, ..Options::default() })?;
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        db.set("key1".to_owned(), "value2".to_owned())?;
+        assert!(db.outdated_len > 0);
         db.compact()?;
-        assert_eq!(db.index.len(), 1);
+        assert_eq!(db.outdated_len, 0);
         assert_eq!(db.get("key1".to_owned())?, Some("value2".to_owned()));
-        assert_eq!(db.get("key2".to_owned())?, None);
         Ok(())
     }
-}
 
+    #[test]
+    fn test_compaction_crash() -> Result<()> {
+        let leftovers = |dir: &std::path::Path| std::fs::read_dir(dir).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with(COMPACTION_PREFIX))
+            .count();
+        for step in [CompactionStep::Copied, CompactionStep::Synced, CompactionStep::Archived, CompactionStep::Renamed] {
+            let tmp = TempDir::new().expect("create new dir err");
+            let options = || Options { retained_segments: 1, ..Options::default() };
+            let mut db = KvStore::open_with(tmp.path(), options())?;
+            db.set("key1".to_owned(), "value1".to_owned())?;
+            db.set("key1".to_owned(), "value2".to_owned())?;
+            db.set("key2".to_owned(), "value3".to_owned())?;
+            db.remove("key2".to_owned())?;
+            db.set("key3".to_owned(), "value4".to_owned())?;
+
+            db.crash_at = Some(step);
+            let crashed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| db.compact()));
+            assert!(crashed.is_err());
+            drop(db);
+
+            //whichever log the crash left in place holds every write, and the partial one is gone
+            let mut db = KvStore::open_with(tmp.path(), options())?;
+            assert_eq!(leftovers(tmp.path()), 0, "after {:?}", step);
+            assert_eq!(db.get("key1".to_owned())?, Some("value2".to_owned()));
+            assert_eq!(db.get("key2".to_owned())?, None);
+            assert_eq!(db.get("key3".to_owned())?, Some("value4".to_owned()));
+            db.set("key4".to_owned(), "value5".to_owned())?;
+            db.compact()?;
+            drop(db);
+            let mut db = KvStore::open_with(tmp.path(), options())?;
+            assert_eq!(db.get("key1".to_owned())?, Some("value2".to_owned()));
+            assert_eq!(db.get("key4".to_owned())?, Some("value5".to_owned()));
+            assert_eq!(db.changes(5)?.next().transpose()?.map(|event| event.sequence()), Some(6));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_faults() -> Result<()> {
+        let vfs = MemoryVfs::new();
+        let dir = std::path::Path::new("/db");
+        vfs.create_dir_all(dir)?;
+        let options = || Options { vfs: Some(std::sync::Arc::new(vfs.clone())), ..Options::default() };
+        let mut db = KvStore::open_with(dir, options())?;
+
+        //short writes are completed, a value larger than the write buffer included
+        vfs.set_short_writes(true);
+        let large = "x".repeat(20000);
+        db.set("key1".to_owned(), large.clone())?;
+        db.set("key2".to_owned(), "value2".to_owned())?;
+        vfs.set_short_writes(false);
+        drop(db);
+        let mut db = KvStore::open_with(dir, options())?;
+        assert_eq!(db.get("key1".to_owned())?, Some(large.clone()));
+        assert_eq!(db.get("key2".to_owned())?, Some("value2".to_owned()));
+
+        //a full disk fails the write without leaving part of its record in the log
+        let used = vfs.open_read(&dir.join(".data"))?.size()?;
+        vfs.set_capacity(Some(used + 10));
+        assert!(db.set("key3".to_owned(), "value3".to_owned()).is_err());
+        vfs.set_capacity(None);
+        assert_eq!(vfs.open_read(&dir.join(".data"))?.size()?, used);
+        db.set("key4".to_owned(), "value4".to_owned())?;
+        drop(db);
+        let mut db = KvStore::open_with(dir, options())?;
+        assert_eq!(db.get("key3".to_owned())?, None);
+        assert_eq!(db.get("key4".to_owned())?, Some("value4".to_owned()));
+
+        //so does a removal, the key stays in the index and after a reopen, failed writes are not counted
+        let writes = db.stats()?.writes;
+        let used = vfs.open_read(&dir.join(".data"))?.size()?;
+        vfs.set_capacity(Some(used + 10));
+        assert!(db.remove("key4".to_owned()).is_err());
+        assert!(db.set("key4".to_owned(), "value5".to_owned()).is_err());
+        vfs.set_capacity(None);
+        assert_eq!(db.stats()?.writes, writes);
+        assert_eq!(db.get("key4".to_owned())?, Some("value4".to_owned()));
+        assert!(db.check_index()?.is_empty());
+        drop(db);
+        let mut db = KvStore::open_with(dir, options())?;
+        assert_eq!(db.get("key4".to_owned())?, Some("value4".to_owned()));
+
+        //a failed sync aborts the compaction, the store keeps its log and serves on
+        vfs.set_fail_syncs(true);
+        assert!(db.compact().is_err());
+        vfs.set_fail_syncs(false);
+        assert_eq!(vfs.read_dir(dir)?, vec![dir.join(".data")]);
+        db.set("key5".to_owned(), "value5".to_owned())?;
+        assert_eq!(db.get("key1".to_owned())?, Some(large.clone()));
+
+        //a power cut loses the writes after the last compaction, which made everything before it durable
+        db.compact()?;
+        db.set("key6".to_owned(), "value6".to_owned())?;
+        drop(db);
+        vfs.power_loss();
+        let mut db = KvStore::open_with(dir, options())?;
+        assert_eq!(db.get("key1".to_owned())?, Some(large));
+        assert_eq!(db.get("key5".to_owned())?, Some("value5".to_owned()));
+        assert_eq!(db.get("key6".to_owned())?, None);
+        Ok(())
+    }
+}
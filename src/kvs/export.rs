@@ -0,0 +1,150 @@
+//!engine-neutral streams of key-value pairs, written by `export` and read back by `import`.
+//!
+//!`json`: one object per line, `{"key":K,"value":V}`, where a key or value is a JSON string when it is
+//!valid UTF-8 and `{"b64":"..."}` holding its base64 otherwise.
+//!
+//!`binary`: the magic bytes `KVSX`, a format version byte (1), then per pair the key length as a
+//!little-endian u32, the key, the value length as a little-endian u32 and the value, up to the end of the stream.
+//!a key or value of 4 GiB or more cannot be written in it.
+use crate::{KvsEngine, Result, Error};
+use serde::{Serialize, Deserialize};
+use std::convert::TryFrom;
+use std::io::{Read, Write, BufRead, BufReader, BufWriter, ErrorKind};
+use std::str::FromStr;
+
+const MAGIC: &[u8] = b"KVSX";
+const VERSION: u8 = 1;
+
+///bytes reserved for a chunk before it is read, longer ones grow as their bytes arrive
+const CHUNK_RESERVE: usize = 64 << 10;
+
+///layout of an export stream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    ///JSON lines, readable and diffable
+    Json,
+    ///length-prefixed bytes, compact for binary data
+    Binary,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ExportFormat::Json),
+            "binary" => Ok(ExportFormat::Binary),
+            _ => Err(format!("unknown format {}, expected json or binary", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Pair {
+    #[serde(with = "crate::bytes")]
+    key: Vec<u8>,
+    #[serde(with = "crate::bytes")]
+    value: Vec<u8>,
+}
+
+///write every live pair of `engine` in key order, read by `KvsEngine::scan` without copying the data
+///first. returns the number of pairs written
+pub fn export(engine: &mut dyn KvsEngine, format: ExportFormat, writer: impl Write) -> Result<u64> {
+    let mut writer = BufWriter::new(writer);
+    if format == ExportFormat::Binary {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+    }
+    let mut count = 0;
+    for pair in engine.scan()? {
+        let (key, value) = pair?;
+        match format {
+            ExportFormat::Json => {
+                serde_json::to_writer(&mut writer, &Pair { key, value })?;
+                writer.write_all(b"\n")?;
+            }
+            ExportFormat::Binary => {
+                for bytes in [key, value] {
+                    let len = u32::try_from(bytes.len()).map_err(|_| Error::SerializingError)?;
+                    writer.write_all(&len.to_le_bytes())?;
+                    writer.write_all(&bytes)?;
+                }
+            }
+        }
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+///set every pair of an export stream into `engine`, returns the number of pairs read
+pub fn import(engine: &mut dyn KvsEngine, format: ExportFormat, reader: impl Read) -> Result<u64> {
+    let mut reader = BufReader::new(reader);
+    let mut count = 0;
+    match format {
+        ExportFormat::Json => {
+            for line in reader.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let pair: Pair = serde_json::from_str(&line)?;
+                engine.set_bytes(pair.key, pair.value)?;
+                count += 1;
+            }
+        }
+        ExportFormat::Binary => {
+            let mut header = [0; 5];
+            reader.read_exact(&mut header).map_err(|_| Error::InvalidEncodingError)?;
+            if &header[..4] != MAGIC || header[4] != VERSION {
+                return Err(Error::InvalidEncodingError);
+            }
+            while let Some(key) = read_chunk(&mut reader, true)? {
+                let value = read_chunk(&mut reader, false)?.ok_or(Error::InvalidEncodingError)?;
+                engine.set_bytes(key, value)?;
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+///copy every live pair from one engine into another as `KvsEngine::scan` reads them, returns the
+///number of pairs copied
+pub fn migrate(from: &mut dyn KvsEngine, to: &mut dyn KvsEngine) -> Result<u64> {
+    let mut count = 0;
+    for pair in from.scan()? {
+        let (key, value) = pair?;
+        to.set_bytes(key, value)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+///a length-prefixed chunk, `None` at the end of the stream when `may_end` allows it there. the memory
+///taken grows with the bytes read, not with the length the stream claims
+fn read_chunk(reader: &mut impl Read, may_end: bool) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    let mut filled = 0;
+    while filled < len.len() {
+        match reader.read(&mut len[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if filled == 0 && may_end {
+        return Ok(None);
+    }
+    if filled < len.len() {
+        return Err(Error::InvalidEncodingError);
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    let mut chunk = Vec::with_capacity(len.min(CHUNK_RESERVE));
+    reader.take(len as u64).read_to_end(&mut chunk)?;
+    if chunk.len() < len {
+        return Err(Error::InvalidEncodingError);
+    }
+    Ok(Some(chunk))
+}
@@ -0,0 +1,47 @@
+use serde::{Serialize, Deserialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+///how long superseded versions of a key survive compaction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Retention {
+    ///keep at most this many prior versions of every key, a removal counts as a version
+    Versions(usize),
+    ///keep the versions that were still current within this duration
+    Age(Duration),
+}
+
+///the point in time a historical read looks at
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum At {
+    ///the state right after the record with this sequence number was written
+    Sequence(u64),
+    ///milliseconds since the unix epoch
+    Timestamp(u64),
+}
+
+///one value a key held, `value` is `None` when the key was removed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Version {
+    ///sequence number of the record that produced the value
+    pub sequence: u64,
+    ///milliseconds since the unix epoch when the record was written
+    pub timestamp: u64,
+    ///the value, `None` for a removal
+    #[serde(with = "crate::bytes::option")]
+    pub value: Option<Vec<u8>>,
+}
+
+impl Version {
+    ///whether the version had already been written at `at`
+    pub fn written_by(&self, at: At) -> bool {
+        match at {
+            At::Sequence(sequence) => self.sequence <= sequence,
+            At::Timestamp(timestamp) => self.timestamp <= timestamp,
+        }
+    }
+}
+
+///milliseconds since the unix epoch, records are stamped with it
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
@@ -0,0 +1,196 @@
+//!offline inspection of the data file of a `KvStore`, the store must not be open while it runs.
+//!`dump`, `verify` and `stats` only read the file, `repair` replaces it
+use crate::err::{Result, Error};
+use crate::kvs::{DiskVfs, Vfs};
+use crate::kvs::database::{Log, LogKind};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+///what a record does to its key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordKind {
+    ///stores a value
+    Set,
+    ///removes the key, a tombstone
+    Remove,
+    ///carries a merge operand
+    Merge,
+}
+
+///a stretch of the data file
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entry {
+    ///a record that parses
+    Record {
+        ///position of the record in the file
+        offset: usize,
+        ///bytes taken by the record
+        len: usize,
+        ///sequence number of the record
+        sequence: u64,
+        ///what the record does
+        kind: RecordKind,
+        ///the key of the record
+        key: Vec<u8>,
+    },
+    ///bytes no record could be parsed from
+    Corrupt {
+        ///position of the bytes in the file
+        offset: usize,
+        ///number of bytes skipped
+        len: usize,
+    },
+}
+
+///space taken by the data file
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogStats {
+    ///records that parse
+    pub records: usize,
+    ///keys holding a value
+    pub keys: usize,
+    ///bytes of the records the index points to
+    pub live_bytes: usize,
+    ///bytes of the records outdated by later ones, reclaimed by compaction
+    pub dead_bytes: usize,
+    ///bytes no record could be parsed from
+    pub corrupt_bytes: usize,
+}
+
+///outcome of `repair`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RepairReport {
+    ///records written into the new log
+    pub kept: usize,
+    ///bytes left out of it
+    pub dropped_bytes: usize,
+    ///where the old log was kept
+    pub corrupt: PathBuf,
+}
+
+///every record and corrupt stretch of the data file in `dir`, in file order
+pub fn dump(dir: &Path) -> Result<Vec<Entry>> {
+    Ok(scan(&read_log(dir)?))
+}
+
+///the problems found in the data file in `dir`, empty when every record parses and their sequence
+///numbers go up
+pub fn verify(dir: &Path) -> Result<Vec<String>> {
+    let mut problems = vec![];
+    let mut last = 0;
+    for entry in dump(dir)? {
+        match entry {
+            Entry::Corrupt { offset, len } => problems.push(format!("{} bytes at {} do not parse as a record", len, offset)),
+            //records written before sequence numbers were kept have 0
+            Entry::Record { offset, sequence, .. } if sequence != 0 => {
+                if sequence <= last {
+                    problems.push(format!("record at {} has sequence number {} after {}", offset, sequence, last));
+                }
+                last = sequence;
+            }
+            Entry::Record { .. } => {}
+        }
+    }
+    Ok(problems)
+}
+
+///live and dead bytes of the data file in `dir`, counted the way the store counts them when it
+///replays the records: a key's latest value and the merge operands after it are live
+pub fn stats(dir: &Path) -> Result<LogStats> {
+    let mut stats = LogStats { records: 0, keys: 0, live_bytes: 0, dead_bytes: 0, corrupt_bytes: 0 };
+    //bytes of the live records of every key
+    let mut live: HashMap<Vec<u8>, usize> = HashMap::new();
+    for entry in dump(dir)? {
+        match entry {
+            Entry::Corrupt { len, .. } => stats.corrupt_bytes += len,
+            Entry::Record { len, kind, key, .. } => {
+                stats.records += 1;
+                match kind {
+                    RecordKind::Set => stats.dead_bytes += live.insert(key, len).unwrap_or(0),
+                    RecordKind::Remove => stats.dead_bytes += live.remove(&key).unwrap_or(0) + len,
+                    RecordKind::Merge => *live.entry(key).or_default() += len,
+                }
+            }
+        }
+    }
+    stats.keys = live.len();
+    stats.live_bytes = live.values().sum();
+    Ok(stats)
+}
+
+///rewrite the records that parse into a new data file, the old one is kept as `.data.corrupt`, or
+///`.data.corrupt.1` and so on when an earlier repair kept one already
+pub fn repair(dir: &Path) -> Result<RepairReport> {
+    let bytes = read_log(dir)?;
+    let corrupt = (0..)
+        .map(|n| dir.join(if n == 0 { ".data.corrupt".to_owned() } else { format!(".data.corrupt.{}", n) }))
+        .find(|path| !path.exists())
+        .unwrap();
+    let mut report = RepairReport { kept: 0, dropped_bytes: 0, corrupt };
+    let mut repaired = File::create(dir.join(".data_repair"))?;
+    for entry in scan(&bytes) {
+        match entry {
+            Entry::Record { offset, len, .. } => {
+                repaired.write_all(&bytes[offset..offset + len])?;
+                report.kept += 1;
+            }
+            Entry::Corrupt { len, .. } => report.dropped_bytes += len,
+        }
+    }
+    repaired.sync_all()?;
+    fs::rename(dir.join(".data"), &report.corrupt)?;
+    fs::rename(dir.join(".data_repair"), dir.join(".data"))?;
+    DiskVfs.sync_dir(dir)?;
+    Ok(report)
+}
+
+fn read_log(dir: &Path) -> Result<Vec<u8>> {
+    if !dir.join(".data").is_file() {
+        return Err(Error::InvalidDirectoryPath);
+    }
+    Ok(fs::read(dir.join(".data"))?)
+}
+
+///parse records one after another, after a broken one the scan resumes at the next position a record
+///parses from. only the starts of records are tried, so a damaged stretch is read about once
+fn scan(bytes: &[u8]) -> Vec<Entry> {
+    let mut entries = vec![];
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let mut stream = serde_json::Deserializer::from_slice(&bytes[offset..]).into_iter::<Log>();
+        match stream.next() {
+            None => break,
+            Some(Ok(log)) => {
+                let len = stream.byte_offset();
+                let kind = match log.kind() {
+                    LogKind::Set => RecordKind::Set,
+                    LogKind::Remove => RecordKind::Remove,
+                    LogKind::Merge => RecordKind::Merge,
+                };
+                entries.push(Entry::Record { offset, len, sequence: log.3, kind, key: log.0 });
+                offset += len;
+            }
+            Some(Err(_)) => {
+                let next = (offset + 1..bytes.len())
+                    .find(|pos| starts_record(&bytes[*pos..]) && parses(&bytes[*pos..]))
+                    .unwrap_or(bytes.len());
+                entries.push(Entry::Corrupt { offset, len: next - offset });
+                offset = next;
+            }
+        }
+    }
+    entries
+}
+
+///whether `bytes` open like a record: `[` and its key, a string, an object holding base64 or an
+///array of numbers. quotes inside strings are escaped, so outside damaged bytes this only matches
+///where a record starts
+fn starts_record(bytes: &[u8]) -> bool {
+    bytes.len() > 1 && bytes[0] == b'[' && matches!(bytes[1], b'"' | b'{' | b'[')
+}
+
+fn parses(bytes: &[u8]) -> bool {
+    matches!(serde_json::Deserializer::from_slice(bytes).into_iter::<Log>().next(), Some(Ok(_)))
+}
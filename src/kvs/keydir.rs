@@ -0,0 +1,214 @@
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::mem::size_of;
+
+///where the records of a key are in the data file: its base record and the merge operands appended
+///after it. the key itself is only kept by the `Keydir`
+#[derive(Debug, Clone)]
+pub(crate) struct Index {
+    start: u64,
+    ///records are serialized in memory, far below 4 GiB
+    len: u32,
+    ///sequence number of the latest record of the key, checked by transactions
+    pub(crate) version: u64,
+    ///boxed so that keys without merges pay a single pointer instead of an empty `Vec`
+    #[allow(clippy::box_collection)]
+    operands: Option<Box<Vec<(u64, u32)>>>,
+}
+
+impl Index {
+    pub(crate) fn new(start: usize, len: usize, version: u64) -> Index {
+        Index { start: start as u64, len: len as u32, version, operands: None }
+    }
+
+    pub(crate) fn start(&self) -> usize {
+        self.start as usize
+    }
+
+    pub(crate) fn end(&self) -> usize {
+        self.start as usize + self.len as usize
+    }
+
+    ///`start..end` of every merge operand, oldest first
+    pub(crate) fn operands(&self) -> impl Iterator<Item=(usize, usize)> + '_ {
+        self.operands.iter().flat_map(|operands| operands.iter())
+            .map(|(start, len)| (*start as usize, *start as usize + *len as usize))
+    }
+
+    pub(crate) fn has_operands(&self) -> bool {
+        self.operands.is_some()
+    }
+
+    pub(crate) fn push_operand(&mut self, start: usize, len: usize) {
+        self.operands.get_or_insert_with(Box::default).push((start as u64, len as u32));
+    }
+
+    ///`start..end` of the base record followed by the ones of the operands
+    pub(crate) fn chain(&self) -> impl Iterator<Item=(usize, usize)> + '_ {
+        std::iter::once((self.start(), self.end())).chain(self.operands())
+    }
+
+    ///bytes taken by the record and its merge operands
+    pub(crate) fn len(&self) -> usize {
+        self.chain().map(|(start, end)| end - start).sum()
+    }
+
+    ///position of the latest record of the chain, which tells its versions apart
+    pub(crate) fn latest(&self) -> usize {
+        self.operands().last().map_or(self.start(), |(start, _)| start)
+    }
+
+    ///heap bytes of the operands
+    fn heap_bytes(&self) -> usize {
+        self.operands.as_ref().map_or(0, |operands| size_of::<Vec<(u64, u32)>>() + operands.capacity() * size_of::<(u64, u32)>())
+    }
+}
+
+///the in-memory index of `KvStore` from keys to their records. it holds every key once, in order,
+///or only a 128-bit hash of it when keys are large or numerous, the records hold the keys anyway.
+///two keys may share a hash, so a hashed index may point at the record of another key: `KvStore`
+///compares the key of the record it finds
+#[derive(Debug, Clone)]
+pub(crate) enum Keydir {
+    Ordered(BTreeMap<Box<[u8]>, Index>),
+    ///seeded at random, the hashes only live as long as the process
+    Hashed(HashMap<u128, Index>, RandomState),
+}
+
+impl Keydir {
+    pub(crate) fn new(hashed: bool) -> Keydir {
+        if hashed { Keydir::Hashed(HashMap::new(), RandomState::new()) } else { Keydir::Ordered(BTreeMap::new()) }
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&Index> {
+        match self {
+            Keydir::Ordered(map) => map.get(key),
+            Keydir::Hashed(map, state) => map.get(&hash(state, key)),
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, key: &[u8]) -> Option<&mut Index> {
+        match self {
+            Keydir::Ordered(map) => map.get_mut(key),
+            Keydir::Hashed(map, state) => map.get_mut(&hash(state, key)),
+        }
+    }
+
+    pub(crate) fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    ///the index replaced
+    pub(crate) fn insert(&mut self, key: Vec<u8>, index: Index) -> Option<Index> {
+        match self {
+            Keydir::Ordered(map) => map.insert(key.into_boxed_slice(), index),
+            Keydir::Hashed(map, state) => map.insert(hash(state, &key), index),
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &[u8]) -> Option<Index> {
+        match self {
+            Keydir::Ordered(map) => map.remove(key),
+            Keydir::Hashed(map, state) => map.remove(&hash(state, key)),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Keydir::Ordered(map) => map.len(),
+            Keydir::Hashed(map, _) => map.len(),
+        }
+    }
+
+    ///every index with its key when it is kept, in key order only for an ordered keydir
+    pub(crate) fn entries(&self) -> Box<dyn Iterator<Item=(Option<&[u8]>, &Index)> + '_> {
+        match self {
+            Keydir::Ordered(map) => Box::new(map.iter().map(|(key, index)| (Some(&key[..]), index))),
+            Keydir::Hashed(map, _) => Box::new(map.values().map(|index| (None, index))),
+        }
+    }
+
+    pub(crate) fn values(&self) -> Box<dyn Iterator<Item=&Index> + '_> {
+        Box::new(self.entries().map(|(_, index)| index))
+    }
+
+    ///a keydir of the same kind holding the indexes `f` returns under the same keys, `None` drops a key
+    pub(crate) fn try_map<E>(&self, mut f: impl FnMut(&Index) -> Result<Option<Index>, E>) -> Result<Keydir, E> {
+        Ok(match self {
+            Keydir::Ordered(map) => {
+                let mut mapped = BTreeMap::new();
+                for (key, index) in map {
+                    if let Some(index) = f(index)? {
+                        mapped.insert(key.clone(), index);
+                    }
+                }
+                Keydir::Ordered(mapped)
+            }
+            Keydir::Hashed(map, state) => {
+                let mut mapped = HashMap::with_capacity(map.len());
+                for (key, index) in map {
+                    if let Some(index) = f(index)? {
+                        mapped.insert(*key, index);
+                    }
+                }
+                Keydir::Hashed(mapped, state.clone())
+            }
+        })
+    }
+
+    ///an estimate of the bytes of memory taken, without the overhead of the allocator
+    pub(crate) fn memory_usage(&self) -> usize {
+        let entry = |key: usize| key + size_of::<Index>();
+        match self {
+            //nodes hold up to 11 entries, keys written in order leave them about half full
+            Keydir::Ordered(map) => map.iter()
+                .map(|(key, index)| entry(size_of::<Box<[u8]>>()) * 2 + key.len() + index.heap_bytes())
+                .sum(),
+            //a control byte per bucket and at least one bucket in eight left free
+            Keydir::Hashed(map, _) => map.capacity() * 8 / 7 * (entry(size_of::<u128>()) + 1)
+                + map.values().map(Index::heap_bytes).sum::<usize>(),
+        }
+    }
+}
+
+///two 64-bit hashes of the key, a collision among 2^64 keys is still unlikely and the random seed
+///keeps clients from choosing keys that collide
+fn hash(state: &RandomState, key: &[u8]) -> u128 {
+    let half = |seed: u8| {
+        let mut hasher = state.build_hasher();
+        seed.hash(&mut hasher);
+        key.hash(&mut hasher);
+        hasher.finish() as u128
+    };
+    half(0) << 64 | half(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Index, Keydir};
+
+    #[test]
+    fn test_keydir() {
+        for hashed in [false, true] {
+            let mut keydir = Keydir::new(hashed);
+            assert!(keydir.insert(b"key1".to_vec(), Index::new(0, 10, 1)).is_none());
+            assert!(keydir.insert(b"key2".to_vec(), Index::new(10, 10, 2)).is_none());
+            let replaced = keydir.insert(b"key1".to_vec(), Index::new(20, 12, 3)).unwrap();
+            assert_eq!((replaced.start(), replaced.end()), (0, 10));
+            keydir.get_mut(b"key1").unwrap().push_operand(32, 5);
+            let index = keydir.get(b"key1").unwrap();
+            assert_eq!(index.chain().collect::<Vec<_>>(), vec![(20, 32), (32, 37)]);
+            assert_eq!((index.len(), index.latest()), (17, 32));
+            assert!(keydir.get(b"key3").is_none());
+
+            let mapped = keydir.try_map(|index| Ok::<_, ()>(Some(Index::new(index.start() + 100, index.end() - index.start(), index.version)).filter(|_| index.has_operands()))).unwrap();
+            assert_eq!(mapped.len(), 1);
+            assert_eq!(mapped.get(b"key1").unwrap().start(), 120);
+            assert!(keydir.remove(b"key2").is_some());
+            assert!(!keydir.contains_key(b"key2"));
+            assert!(keydir.memory_usage() > 0);
+            assert_eq!(keydir.entries().next().unwrap().0.is_some(), !hashed);
+        }
+    }
+}
@@ -0,0 +1,522 @@
+use crate::{KvsEngine, Snapshot, Pairs, Version, Event, Watch, Changes, Stats, Result, Error, CompareAndSwapError, CompareAndSwapResult};
+use crate::kvs::{Database, DiskVfs, MergeOperator, Options, Retention, Transaction, Watchers, CompactionPolicy, now_millis};
+use crate::kvs::backup::prepare_dest;
+use crate::kvs::merge::add_to_counter;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+///an engine keeping everything in memory, for tests and throwaway servers. clones share the same data,
+///so it can be handed to several threads. superseded versions are kept with a retention, and every
+///mutation since the last compaction is kept for `changes`. it compacts on its own under the same policy
+///as `KvStore`, counting the bytes of the kept mutations as outdated
+#[derive(Clone)]
+pub struct MemoryKvsEngine {
+    inner: Arc<RwLock<Inner>>,
+    merge_operator: Option<MergeOperator>,
+    retention: Option<Retention>,
+    compaction: CompactionPolicy,
+}
+
+#[derive(Default)]
+struct Inner {
+    ///shared with snapshots, writes copy it when a snapshot still holds it
+    data: Arc<BTreeMap<Vec<u8>, Entry>>,
+    ///superseded versions and removals of every key, oldest first, only tracked with a retention
+    history: BTreeMap<Vec<u8>, Vec<Version>>,
+    ///every mutation after `base`, in order
+    log: Vec<Event>,
+    ///bytes of the keys, values and operands in `log`
+    log_bytes: usize,
+    ///bytes of the keys and values in `data`
+    live_bytes: usize,
+    base: u64,
+    sequence: u64,
+    ///sequence numbers of the removals since `base`, the versions of those keys while absent, the
+    ///other absent keys have `base`
+    removed: HashMap<Vec<u8>, u64>,
+    watchers: Watchers,
+    reads: AtomicU64,
+    writes: u64,
+    compactions: u64,
+    last_compaction: Option<Duration>,
+}
+
+#[derive(Clone)]
+struct Entry {
+    value: Vec<u8>,
+    ///sequence number of the write that stored the value
+    version: u64,
+    timestamp: u64,
+}
+
+impl Entry {
+    fn to_version(&self) -> Version {
+        Version { sequence: self.version, timestamp: self.timestamp, value: Some(self.value.clone()) }
+    }
+}
+
+impl Default for MemoryKvsEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryKvsEngine {
+    ///an empty engine
+    pub fn new() -> Self {
+        Self::with_options(Options::default())
+    }
+
+    ///an empty engine using the merge operator and retention of `options`, the other options concern files
+    pub fn with_options(options: Options) -> Self {
+        MemoryKvsEngine {
+            inner: Arc::new(RwLock::new(Inner::default())),
+            merge_operator: options.merge_operator,
+            retention: options.retention,
+            compaction: options.compaction,
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Inner> {
+        self.inner.read().unwrap()
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Inner> {
+        self.inner.write().unwrap()
+    }
+
+    ///compact when the policy asks for it, called after every write
+    fn maybe_compact(&self, inner: &mut Inner) {
+        if self.compaction.should_compact(inner.log_bytes, inner.live_bytes) {
+            inner.compact(self.retention);
+        }
+    }
+}
+
+impl Inner {
+    ///store `value` at `key`, or remove it when `None`, and return the sequence number of the write
+    fn put(&mut self, key: Vec<u8>, value: Option<Vec<u8>>, retention: bool) -> u64 {
+        self.sequence += 1;
+        self.writes += 1;
+        let (sequence, timestamp) = (self.sequence, now_millis());
+        let data = Arc::make_mut(&mut self.data);
+        let size = key.len() + value.as_ref().map_or(0, Vec::len);
+        let old = match value {
+            Some(value) => {
+                self.removed.remove(&key);
+                data.insert(key.clone(), Entry { value, version: sequence, timestamp })
+            }
+            None => {
+                self.removed.insert(key.clone(), sequence);
+                data.remove(&key)
+            }
+        };
+        if data.contains_key(&key) {
+            self.live_bytes += size;
+        }
+        if let Some(old) = &old {
+            self.live_bytes -= key.len() + old.value.len();
+        }
+        if retention {
+            let removed = !data.contains_key(&key);
+            let versions = self.history.entry(key).or_default();
+            versions.extend(old.map(|old| old.to_version()));
+            if removed {
+                versions.push(Version { sequence, timestamp, value: None });
+            }
+        }
+        sequence
+    }
+
+    fn publish(&mut self, event: Event) {
+        self.record(event.clone());
+        self.watchers.notify(event);
+    }
+
+    ///keep a mutation for `changes`
+    fn record(&mut self, event: Event) {
+        self.log_bytes += match &event {
+            Event::Set { key, value, .. } => key.len() + value.len(),
+            Event::Merge { key, operand, .. } => key.len() + operand.len(),
+            Event::Remove { key, .. } => key.len(),
+        };
+        self.log.push(event);
+    }
+
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, retention: bool) {
+        let sequence = self.put(key.clone(), Some(value.clone()), retention);
+        self.publish(Event::Set { sequence, key, value });
+    }
+
+    fn remove(&mut self, key: Vec<u8>, retention: bool) {
+        let sequence = self.put(key.clone(), None, retention);
+        self.publish(Event::Remove { sequence, key });
+    }
+
+    ///the version a transaction reads for `key`, an absent key keeps the one of its removal so that
+    ///a key set and removed again in between is a change too
+    fn version(&self, key: &[u8]) -> u64 {
+        match self.data.get(key) {
+            Some(entry) => entry.version,
+            None => self.removed.get(key).copied().unwrap_or(self.base),
+        }
+    }
+
+    ///drop the versions the retention no longer covers, a version was current until the next one was written
+    fn prune(&mut self, retention: Retention) {
+        let cutoff = match retention {
+            Retention::Versions(count) => {
+                for versions in self.history.values_mut() {
+                    versions.drain(..versions.len().saturating_sub(count));
+                }
+                return;
+            }
+            Retention::Age(age) => now_millis().saturating_sub(age.as_millis() as u64),
+        };
+        for (key, versions) in self.history.iter_mut() {
+            let current = self.data.get(key).map(|entry| entry.timestamp);
+            let first = (0..versions.len())
+                .find(|i| {
+                    let until = versions.get(i + 1).map(|next| next.timestamp).or(current).unwrap_or(versions[*i].timestamp);
+                    until >= cutoff
+                })
+                .unwrap_or(versions.len());
+            versions.drain(..first);
+        }
+    }
+
+    ///prune the versions the retention no longer covers and forget the mutations kept for `changes`
+    fn compact(&mut self, retention: Option<Retention>) {
+        let started = Instant::now();
+        match retention {
+            Some(retention) => self.prune(retention),
+            None => self.history.clear(),
+        }
+        self.history.retain(|_, versions| !versions.is_empty());
+        self.log.clear();
+        self.log_bytes = 0;
+        self.removed.clear();
+        self.base = self.sequence;
+        self.compactions += 1;
+        self.last_compaction = Some(started.elapsed());
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let mut inner = self.write();
+        inner.set(key, value, self.retention.is_some());
+        self.maybe_compact(&mut inner);
+        Ok(())
+    }
+
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let inner = self.read();
+        inner.reads.fetch_add(1, Ordering::Relaxed);
+        Ok(inner.data.get(&key).map(|entry| entry.value.clone()))
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        let mut inner = self.write();
+        if !inner.data.contains_key(&key) {
+            return Err(Error::KeyNotFoundError);
+        }
+        inner.remove(key, self.retention.is_some());
+        self.maybe_compact(&mut inner);
+        Ok(())
+    }
+
+    ///the write lock is held from the comparison to the write
+    fn compare_and_swap_bytes(&mut self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<CompareAndSwapResult<Vec<u8>>> {
+        let mut inner = self.write();
+        let current = inner.data.get(&key).map(|entry| entry.value.clone());
+        if current != expected {
+            return Ok(Err(CompareAndSwapError { current }));
+        }
+        match new {
+            Some(value) => inner.set(key, value, self.retention.is_some()),
+            None if current.is_some() => inner.remove(key, self.retention.is_some()),
+            None => {}
+        }
+        self.maybe_compact(&mut inner);
+        Ok(Ok(()))
+    }
+
+    fn increment_bytes(&mut self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let mut inner = self.write();
+        let value = add_to_counter(inner.data.get(&key).map(|entry| entry.value.as_slice()), delta)?;
+        inner.set(key, value.to_string().into_bytes(), self.retention.is_some());
+        self.maybe_compact(&mut inner);
+        Ok(value)
+    }
+
+    ///the operand is folded into the value right away, a merge into nothing removes the key
+    fn merge_bytes(&mut self, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        let merge_operator = self.merge_operator.as_ref().ok_or(Error::NoMergeOperatorError)?;
+        let mut inner = self.write();
+        let old = inner.data.get(&key).map(|entry| entry.value.as_slice());
+        let value = merge_operator.merge(&key, old, &operand);
+        if value.is_none() && old.is_none() {
+            inner.sequence += 1;
+            inner.writes += 1;
+        } else {
+            inner.put(key.clone(), value.clone(), self.retention.is_some());
+        }
+        let sequence = inner.sequence;
+        inner.record(Event::Merge { sequence, key: key.clone(), operand: operand.clone() });
+        inner.watchers.notify_merge(sequence, key, operand, value);
+        self.maybe_compact(&mut inner);
+        Ok(())
+    }
+
+    ///the snapshot shares the current map, later writes copy it
+    fn snapshot(&mut self) -> Result<Box<dyn Snapshot>> {
+        let inner = self.read();
+        Ok(Box::new(MemorySnapshot { sequence: inner.sequence, data: inner.data.clone() }))
+    }
+
+    fn get_versioned(&mut self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)> {
+        let inner = self.read();
+        inner.reads.fetch_add(1, Ordering::Relaxed);
+        Ok((inner.data.get(&key).map(|entry| entry.value.clone()), inner.version(&key)))
+    }
+
+    fn commit(&mut self, txn: Transaction) -> Result<()> {
+        let mut inner = self.write();
+        if txn.reads().iter().any(|(key, version)| inner.version(key) != *version) {
+            return Err(Error::TransactionConflictError);
+        }
+        for (key, value) in txn.into_writes() {
+            match value {
+                Some(value) => inner.set(key, value, self.retention.is_some()),
+                None if inner.data.contains_key(&key) => inner.remove(key, self.retention.is_some()),
+                None => {}
+            }
+        }
+        self.maybe_compact(&mut inner);
+        Ok(())
+    }
+
+    fn history_bytes(&mut self, key: Vec<u8>) -> Result<Vec<Version>> {
+        let inner = self.read();
+        let retained = inner.history.get(&key).into_iter().flatten().cloned();
+        Ok(retained.chain(inner.data.get(&key).map(Entry::to_version)).collect())
+    }
+
+    fn watch(&mut self, prefix: Vec<u8>) -> Result<Watch> {
+        Ok(self.write().watchers.add(prefix))
+    }
+
+    ///the kept mutations are copied out under the lock the live feed is registered with, so none is missed
+    fn changes(&mut self, since: u64) -> Result<Changes> {
+        let mut inner = self.write();
+        if since < inner.base {
+            return Err(Error::SequenceUnavailableError);
+        }
+        let stored: Vec<Event> = inner.log.iter().filter(|event| event.sequence() > since).cloned().collect();
+        let live = inner.watchers.add_feed();
+        Ok(Box::new(stored.into_iter().chain(live).map(Ok)))
+    }
+
+    ///the pairs are written into a `KvStore` at `dest`
+    fn backup(&mut self, dest: &Path) -> Result<()> {
+        let data = self.read().data.clone();
+        prepare_dest(&DiskVfs, dest)?;
+        let mut backup = Database::open(dest)?;
+        for (key, entry) in data.iter() {
+            backup.set_bytes(key.clone(), entry.value.clone())?;
+        }
+        Ok(())
+    }
+
+    ///the retained versions are reported as dead bytes
+    fn stats(&mut self) -> Result<Stats> {
+        let inner = self.read();
+        Ok(Stats {
+            keys: inner.data.len() as u64,
+            live_bytes: inner.live_bytes as u64,
+            dead_bytes: inner.history.values().flatten().map(|version| version.value.as_ref().map_or(0, Vec::len) as u64).sum(),
+            compactions: inner.compactions,
+            last_compaction: inner.last_compaction,
+            reads: inner.reads.load(Ordering::Relaxed),
+            writes: inner.writes,
+            ..Stats::default()
+        })
+    }
+
+    ///prune the versions the retention no longer covers and forget the mutations kept for `changes`
+    fn compact(&mut self) -> Result<()> {
+        self.write().compact(self.retention);
+        Ok(())
+    }
+}
+
+struct MemorySnapshot {
+    sequence: u64,
+    data: Arc<BTreeMap<Vec<u8>, Entry>>,
+}
+
+impl Snapshot for MemorySnapshot {
+    fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.data.get(&key).map(|entry| entry.value.clone()))
+    }
+
+    fn iter(&mut self) -> Pairs<'_> {
+        Box::new(self.data.iter().map(|(key, entry)| Ok((key.clone(), entry.value.clone()))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use crate::err::{Result, Error, CompareAndSwapError};
+    use crate::{MemoryKvsEngine, KvStore, KvsEngine};
+    use crate::kvs::{MergeOperator, Options, Retention, Transaction, Event, CompactionPolicy};
+
+    #[test]
+    fn test_get_remove() -> Result<()> {
+        let mut db = MemoryKvsEngine::new();
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        db.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(db.get("key1".to_owned())?, Some("value2".to_owned()));
+        db.remove("key1".to_owned())?;
+        assert_eq!(db.get("key1".to_owned())?, None);
+        assert!(matches!(db.remove("key1".to_owned()), Err(Error::KeyNotFoundError)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_and_swap_increment_merge() -> Result<()> {
+        let mut db = MemoryKvsEngine::with_options(Options { merge_operator: Some(MergeOperator::Append), ..Options::default() });
+        assert_eq!(db.set_if_absent("key1".to_owned(), "value1".to_owned())?, Ok(()));
+        assert_eq!(db.set_if_absent("key1".to_owned(), "value2".to_owned())?,
+                   Err(CompareAndSwapError { current: Some("value1".to_owned()) }));
+        assert_eq!(db.compare_and_swap("key1".to_owned(), Some("value1".to_owned()), None)?, Ok(()));
+        assert_eq!(db.get("key1".to_owned())?, None);
+
+        assert_eq!(db.increment("counter".to_owned(), 5)?, 5);
+        assert_eq!(db.increment("counter".to_owned(), -7)?, -2);
+
+        db.merge("key2".to_owned(), "a".to_owned())?;
+        db.merge("key2".to_owned(), "b".to_owned())?;
+        assert_eq!(db.get("key2".to_owned())?, Some("ab".to_owned()));
+        assert!(matches!(MemoryKvsEngine::new().merge("key2".to_owned(), "a".to_owned()), Err(Error::NoMergeOperatorError)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_transaction() -> Result<()> {
+        let mut db = MemoryKvsEngine::new();
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        let mut snapshot = db.snapshot()?;
+        db.set("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+
+        let mut txn = Transaction::new();
+        assert_eq!(txn.get(&mut db, b"key1".to_vec())?, Some(b"value2".to_vec()));
+        txn.set(b"key2".to_vec(), b"value3".to_vec());
+        db.set("key1".to_owned(), "value4".to_owned())?;
+        assert!(matches!(db.commit(txn), Err(Error::TransactionConflictError)));
+        assert_eq!(db.get("key2".to_owned())?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_history() -> Result<()> {
+        let mut db = MemoryKvsEngine::with_options(Options { retention: Some(Retention::Versions(1)), ..Options::default() });
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        db.set("key1".to_owned(), "value2".to_owned())?;
+        db.remove("key1".to_owned())?;
+        db.set("key1".to_owned(), "value3".to_owned())?;
+        let values = |db: &mut MemoryKvsEngine| -> Result<Vec<Option<Vec<u8>>>> {
+            Ok(db.history("key1".to_owned())?.into_iter().map(|version| version.value).collect())
+        };
+        assert_eq!(values(&mut db)?, vec![Some(b"value1".to_vec()), Some(b"value2".to_vec()), None, Some(b"value3".to_vec())]);
+        db.compact()?;
+        assert_eq!(values(&mut db)?, vec![None, Some(b"value3".to_vec())]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch_changes() -> Result<()> {
+        let mut db = MemoryKvsEngine::new();
+        let mut watch = db.watch(b"key".to_vec())?;
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        db.set("other".to_owned(), "value2".to_owned())?;
+        db.remove("key1".to_owned())?;
+        assert_eq!(watch.next(), Some(Event::Set { sequence: 1, key: b"key1".to_vec(), value: b"value1".to_vec() }));
+        assert_eq!(watch.next(), Some(Event::Remove { sequence: 3, key: b"key1".to_vec() }));
+
+        let mut changes = db.changes(1)?;
+        db.set("key2".to_owned(), "value3".to_owned())?;
+        let sequences: Vec<u64> = changes.by_ref().take(3).map(|event| event.map(|event| event.sequence())).collect::<Result<_>>()?;
+        assert_eq!(sequences, vec![2, 3, 4]);
+
+        db.compact()?;
+        assert!(matches!(db.changes(3), Err(Error::SequenceUnavailableError)));
+        let mut changes = db.changes(4)?;
+        db.set("key3".to_owned(), "value4".to_owned())?;
+        assert_eq!(changes.next().transpose()?.map(|event| event.sequence()), Some(5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_stats() -> Result<()> {
+        let mut db = MemoryKvsEngine::new();
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        db.get("key1".to_owned())?;
+        let stats = db.stats()?;
+        assert_eq!((stats.keys, stats.live_bytes, stats.reads, stats.writes), (1, 10, 1, 1));
+
+        let tmp = TempDir::new().expect("create new dir err");
+        db.backup(tmp.path())?;
+        let mut restored = KvStore::open(tmp.path())?;
+        assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_between_threads() -> Result<()> {
+        let db = MemoryKvsEngine::new();
+        let handles: Vec<_> = (0..4).map(|i| {
+            let mut db = db.clone();
+            std::thread::spawn(move || db.set(format!("key{}", i), "value".to_owned()))
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        assert_eq!(db.clone().stats()?.keys, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_bounded() -> Result<()> {
+        let compaction = CompactionPolicy { dead_bytes: 1000, ..CompactionPolicy::default() };
+        let mut db = MemoryKvsEngine::with_options(Options { compaction, ..Options::default() });
+        for i in 0..1000 {
+            db.set("key1".to_owned(), format!("value{}", i))?;
+        }
+        //the mutations kept for `changes` are dropped once they pass the policy
+        let inner = db.read();
+        assert!(inner.log_bytes < 1000 && inner.log.len() < 100);
+        assert_eq!(inner.live_bytes, "key1value999".len());
+        assert!(inner.compactions > 0 && inner.base > 900);
+        drop(inner);
+        assert!(matches!(db.changes(0), Err(Error::SequenceUnavailableError)));
+
+        let compaction = CompactionPolicy { disabled: true, ..CompactionPolicy::default() };
+        let mut db = MemoryKvsEngine::with_options(Options { compaction, ..Options::default() });
+        for i in 0..1000 {
+            db.set("key1".to_owned(), format!("value{}", i))?;
+        }
+        assert_eq!(db.read().log.len(), 1000);
+        Ok(())
+    }
+}
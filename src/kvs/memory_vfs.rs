@@ -0,0 +1,371 @@
+use crate::kvs::vfs::{Vfs, VfsFile};
+use std::collections::BTreeMap;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+///a disk simulated in memory for tests. it keeps what was synced apart from what was only written,
+///so `power_loss` can drop the latter, and fails writes and syncs on demand.
+///clones share the same disk
+#[derive(Clone, Default)]
+pub struct MemoryVfs {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    ///files by inode, a removed file lives on for the handles still open
+    inodes: Vec<Inode>,
+    entries: BTreeMap<PathBuf, Node>,
+    ///the entries as of the latest sync of their directory, directories are durable once created
+    durable: BTreeMap<PathBuf, Node>,
+    ///bytes the files may hold together
+    capacity: Option<u64>,
+    short_writes: bool,
+    fail_syncs: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Node {
+    File(usize),
+    Dir,
+}
+
+#[derive(Default)]
+struct Inode {
+    data: Vec<u8>,
+    synced: Vec<u8>,
+}
+
+impl MemoryVfs {
+    ///an empty disk
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///fail writes with `StorageFull` once the files hold `capacity` bytes, `None` lifts the limit
+    pub fn set_capacity(&self, capacity: Option<u64>) {
+        self.lock().capacity = capacity;
+    }
+
+    ///store at most half of every write and report the shorter length, as a write interrupted by a signal
+    pub fn set_short_writes(&self, short_writes: bool) {
+        self.lock().short_writes = short_writes;
+    }
+
+    ///fail every file and directory sync
+    pub fn set_fail_syncs(&self, fail_syncs: bool) {
+        self.lock().fail_syncs = fail_syncs;
+    }
+
+    ///lose every write and directory change that was not synced, as the machine would on a power cut.
+    ///handles still open afterwards see the surviving data
+    pub fn power_loss(&self) {
+        let mut state = self.lock();
+        state.entries = state.durable.clone();
+        for inode in state.inodes.iter_mut() {
+            inode.data = inode.synced.clone();
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn open(&self, path: &Path, create: bool, exclusive: bool, truncate: bool, write: bool) -> io::Result<Box<dyn VfsFile>> {
+        let mut state = self.lock();
+        let inode = match state.entries.get(path) {
+            Some(Node::Dir) => return Err(io::Error::other("is a directory")),
+            Some(Node::File(_)) if exclusive => return Err(ErrorKind::AlreadyExists.into()),
+            Some(Node::File(inode)) => *inode,
+            None if !create => return Err(ErrorKind::NotFound.into()),
+            None => {
+                state.check_parent(path)?;
+                state.inodes.push(Inode::default());
+                let inode = state.inodes.len() - 1;
+                state.entries.insert(path.to_owned(), Node::File(inode));
+                inode
+            }
+        };
+        if truncate {
+            state.inodes[inode].data.clear();
+        }
+        Ok(Box::new(MemoryFile { vfs: self.clone(), inode, pos: 0, write, append: write && !truncate }))
+    }
+}
+
+impl State {
+    fn check_parent(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if self.entries.get(parent) == Some(&Node::Dir) => Ok(()),
+            _ => Err(ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn used(&self) -> u64 {
+        let mut inodes: Vec<usize> = self.entries.values()
+            .filter_map(|node| match node {
+                Node::File(inode) => Some(*inode),
+                Node::Dir => None,
+            })
+            .collect();
+        inodes.sort_unstable();
+        inodes.dedup();
+        inodes.into_iter().map(|inode| self.inodes[inode].data.len() as u64).sum()
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        if self.fail_syncs {
+            return Err(io::Error::other("injected sync failure"));
+        }
+        Ok(())
+    }
+}
+
+impl Vfs for MemoryVfs {
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        self.open(path, true, false, false, true)
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        self.open(path, true, true, false, true)
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        self.open(path, true, false, true, true)
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        self.open(path, false, false, false, false)
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.lock();
+        if state.entries.get(dir) != Some(&Node::Dir) {
+            return Err(ErrorKind::NotFound.into());
+        }
+        Ok(state.entries.keys().filter(|path| path.parent() == Some(dir)).cloned().collect())
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        for ancestor in dir.ancestors() {
+            match state.entries.get(ancestor) {
+                Some(Node::File(_)) => return Err(ErrorKind::AlreadyExists.into()),
+                Some(Node::Dir) => break,
+                None => {
+                    state.entries.insert(ancestor.to_owned(), Node::Dir);
+                    state.durable.insert(ancestor.to_owned(), Node::Dir);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        matches!(self.lock().entries.get(path), Some(Node::File(_)))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.lock().entries.get(path) == Some(&Node::Dir)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        match state.entries.get(path) {
+            Some(Node::File(_)) => drop(state.entries.remove(path)),
+            Some(Node::Dir) => return Err(io::Error::other("is a directory")),
+            None => return Err(ErrorKind::NotFound.into()),
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        let node = match state.entries.get(from) {
+            Some(Node::File(inode)) => Node::File(*inode),
+            _ => return Err(ErrorKind::NotFound.into()),
+        };
+        state.check_parent(to)?;
+        state.entries.remove(from);
+        state.entries.insert(to.to_owned(), node);
+        Ok(())
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        let node = match state.entries.get(from) {
+            Some(Node::File(inode)) => Node::File(*inode),
+            _ => return Err(ErrorKind::NotFound.into()),
+        };
+        if state.entries.contains_key(to) {
+            return Err(ErrorKind::AlreadyExists.into());
+        }
+        state.check_parent(to)?;
+        state.entries.insert(to.to_owned(), node);
+        Ok(())
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.sync()?;
+        let State { entries, durable, .. } = &mut *state;
+        durable.retain(|path, _| path.parent() != Some(dir) || entries.contains_key(path));
+        for (path, node) in entries.iter().filter(|(path, _)| path.parent() == Some(dir)) {
+            durable.insert(path.clone(), *node);
+        }
+        Ok(())
+    }
+}
+
+struct MemoryFile {
+    vfs: MemoryVfs,
+    inode: usize,
+    pos: u64,
+    write: bool,
+    append: bool,
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let state = self.vfs.lock();
+        let data = &state.inodes[self.inode].data;
+        let start = (self.pos as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.write {
+            return Err(io::Error::other("file not opened for writing"));
+        }
+        let mut state = self.vfs.lock();
+        let len = state.inodes[self.inode].data.len();
+        if self.append {
+            self.pos = len as u64;
+        }
+        let pos = self.pos as usize;
+        let mut stored = buf.len();
+        if state.short_writes && stored > 1 {
+            stored /= 2;
+        }
+        if let Some(capacity) = state.capacity {
+            let free = capacity.saturating_sub(state.used()) as usize;
+            stored = stored.min(len.saturating_sub(pos) + free);
+            if stored == 0 && !buf.is_empty() {
+                return Err(io::Error::new(ErrorKind::StorageFull, "no space left on the simulated disk"));
+            }
+        }
+        let data = &mut state.inodes[self.inode].data;
+        if data.len() < pos {
+            data.resize(pos, 0);
+        }
+        let overlap = stored.min(data.len() - pos);
+        data[pos..pos + overlap].copy_from_slice(&buf[..overlap]);
+        data.extend_from_slice(&buf[overlap..stored]);
+        self.pos += stored as u64;
+        Ok(stored)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.vfs.lock().inodes[self.inode].data.len() as i64;
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "seek before the start of the file"));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+impl VfsFile for MemoryFile {
+    fn sync_all(&self) -> io::Result<()> {
+        let mut state = self.vfs.lock();
+        state.sync()?;
+        let inode = &mut state.inodes[self.inode];
+        inode.synced = inode.data.clone();
+        Ok(())
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.vfs.lock().inodes[self.inode].data.len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.vfs.lock().inodes[self.inode].data.resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(MemoryFile { vfs: self.vfs.clone(), inode: self.inode, pos: self.pos, write: self.write, append: self.append }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kvs::{MemoryVfs, Vfs};
+    use std::io::{ErrorKind, Read, Write};
+    use std::path::Path;
+
+    fn read(vfs: &MemoryVfs, path: &str) -> Vec<u8> {
+        let mut data = vec![];
+        vfs.open_read(Path::new(path)).unwrap().read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_power_loss() {
+        let vfs = MemoryVfs::new();
+        vfs.create_dir_all(Path::new("/db")).unwrap();
+        let mut synced = vfs.create_new(Path::new("/db/synced")).unwrap();
+        synced.write_all(b"kept").unwrap();
+        synced.sync_all().unwrap();
+        vfs.sync_dir(Path::new("/db")).unwrap();
+        synced.write_all(b" lost").unwrap();
+        let mut unlinked = vfs.create_new(Path::new("/db/unlinked")).unwrap();
+        unlinked.write_all(b"data").unwrap();
+        unlinked.sync_all().unwrap();
+        vfs.rename(Path::new("/db/unlinked"), Path::new("/db/renamed")).unwrap();
+        assert_eq!(read(&vfs, "/db/synced"), b"kept lost");
+
+        vfs.power_loss();
+        assert_eq!(read(&vfs, "/db/synced"), b"kept");
+        assert_eq!(vfs.read_dir(Path::new("/db")).unwrap(), vec![Path::new("/db/synced")]);
+    }
+
+    #[test]
+    fn test_faults() {
+        let vfs = MemoryVfs::new();
+        vfs.create_dir_all(Path::new("/db")).unwrap();
+        let mut file = vfs.open_append(Path::new("/db/file")).unwrap();
+
+        vfs.set_short_writes(true);
+        assert_eq!(file.write(b"abcd").unwrap(), 2);
+        file.write_all(b"efgh").unwrap();
+        vfs.set_short_writes(false);
+        assert_eq!(read(&vfs, "/db/file"), b"abefgh");
+
+        vfs.set_capacity(Some(8));
+        assert_eq!(file.write_all(b"ijk").unwrap_err().kind(), ErrorKind::StorageFull);
+        assert_eq!(read(&vfs, "/db/file"), b"abefghij");
+        vfs.set_capacity(None);
+
+        vfs.set_fail_syncs(true);
+        assert!(file.sync_all().is_err());
+        assert!(vfs.sync_dir(Path::new("/db")).is_err());
+    }
+}
@@ -0,0 +1,54 @@
+use std::sync::Arc;
+use crate::err::{Result, Error};
+
+///signature of a custom merge function: (key, old value, operand) -> new value, `None` removes the key
+pub type MergeFn = dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Option<Vec<u8>> + Send + Sync;
+
+///combines a stored value with a merge operand, registered when the engine is opened
+#[derive(Clone)]
+pub enum MergeOperator {
+    ///concatenate the operand to the old value
+    Append,
+    ///keep the greater one, compared as integers when both parse, otherwise as bytes
+    Max,
+    ///a closure registered by the user
+    Custom(Arc<MergeFn>),
+}
+
+impl MergeOperator {
+    ///wrap a closure as a merge operator
+    pub fn custom(f: impl Fn(&[u8], Option<&[u8]>, &[u8]) -> Option<Vec<u8>> + Send + Sync + 'static) -> Self {
+        MergeOperator::Custom(Arc::new(f))
+    }
+
+    ///fold one operand into the old value
+    pub fn merge(&self, key: &[u8], old: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            MergeOperator::Append => Some([old.unwrap_or_default(), operand].concat()),
+            MergeOperator::Max => match old {
+                None => Some(operand.to_vec()),
+                Some(old) => {
+                    let operand_is_greater = match (parse_integer(old), parse_integer(operand)) {
+                        (Some(old), Some(operand)) => operand > old,
+                        _ => operand > old,
+                    };
+                    Some(if operand_is_greater { operand } else { old }.to_vec())
+                }
+            },
+            MergeOperator::Custom(f) => f(key, old, operand),
+        }
+    }
+}
+
+fn parse_integer(bytes: &[u8]) -> Option<i64> {
+    std::str::from_utf8(bytes).ok()?.parse().ok()
+}
+
+///add `delta` to a counter stored as a decimal string, an absent counter counts as 0
+pub(crate) fn add_to_counter(old: Option<&[u8]>, delta: i64) -> Result<i64> {
+    let old = match old {
+        None => 0,
+        Some(old) => parse_integer(old).ok_or(Error::InvalidNumberError)?,
+    };
+    old.checked_add(delta).ok_or(Error::InvalidNumberError)
+}
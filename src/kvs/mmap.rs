@@ -0,0 +1,99 @@
+use memmap2::Mmap;
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+///a read-only memory map of the data file. records are only ever appended behind the mapped ones and
+///compaction writes another file, so the mapped bytes never change. a record past the end of the map
+///swaps in a map of the grown file, clones share it and read it through `&self` from any thread
+#[derive(Clone)]
+pub(crate) struct MappedFile {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    file: File,
+    ///only locked to clone or swap the `Arc`, the bytes are read without it
+    map: RwLock<Arc<Mmap>>,
+}
+
+///bytes of a map, which stays alive while they are borrowed even when a longer one was swapped in
+pub(crate) struct MappedSlice {
+    map: Arc<Mmap>,
+    start: usize,
+    end: usize,
+}
+
+impl Deref for MappedSlice {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map[self.start..self.end]
+    }
+}
+
+impl MappedFile {
+    pub(crate) fn open(path: &Path) -> io::Result<MappedFile> {
+        let file = File::open(path)?;
+        let map = RwLock::new(Arc::new(map(&file)?));
+        Ok(MappedFile { shared: Arc::new(Shared { file, map }) })
+    }
+
+    ///the bytes of `start..end`, which must have been written before
+    pub(crate) fn get(&self, start: usize, end: usize) -> io::Result<MappedSlice> {
+        let mut current = self.shared.map.read().unwrap().clone();
+        if end > current.len() {
+            let mut map = self.shared.map.write().unwrap();
+            //another reader may have swapped it while the lock was released
+            if end > map.len() {
+                *map = Arc::new(self::map(&self.shared.file)?);
+            }
+            current = map.clone();
+        }
+        if start > end || end > current.len() {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        Ok(MappedSlice { map: current, start, end })
+    }
+}
+
+fn map(file: &File) -> io::Result<Mmap> {
+    //SAFETY: the store never rewrites or truncates the bytes it already indexed, see `MappedFile`,
+    //the file must not be modified by another process while the store is open
+    unsafe { Mmap::map(file) }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kvs::mmap::MappedFile;
+    use std::fs::OpenOptions;
+    use std::io::{self, Write};
+    use std::thread;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_shared_reads() -> io::Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
+        let path = tmp.path().join(".data");
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.write_all(b"value1")?;
+        let map = MappedFile::open(&path)?;
+        let before = map.get(0, 6)?;
+
+        //readers on other threads share the map and see the bytes appended after it was taken
+        file.write_all(b"value2")?;
+        let readers: Vec<_> = (0..4).map(|_| {
+            let map = map.clone();
+            thread::spawn(move || map.get(6, 12).map(|slice| slice.to_vec()))
+        }).collect();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap()?, b"value2");
+        }
+        assert_eq!(&*before, b"value1");
+        assert_eq!(&*map.get(0, 12)?, b"value1value2");
+        assert_eq!(map.get(6, 13).err().map(|e| e.kind()), Some(io::ErrorKind::UnexpectedEof));
+        Ok(())
+    }
+}
@@ -1,6 +1,36 @@
+
 mod database;
-mod utils;
 mod sled;
+mod memory;
+mod merge;
+mod options;
+mod transaction;
+mod history;
+mod watch;
+mod segments;
+mod backup;
+mod export;
+pub mod inspect;
+mod stats;
+mod compaction;
+mod vfs;
+mod memory_vfs;
+mod mmap;
+mod cache;
+mod keydir;
 pub use self::database::Database;
 pub use self::sled::SledKvsEngine;
-
+pub use self::memory::MemoryKvsEngine;
+pub use self::merge::{MergeOperator, MergeFn};
+pub use self::options::{Options, ProgressFn};
+pub use self::transaction::{Transaction, TransactionTarget, run_transaction};
+pub use self::history::{Retention, At, Version};
+pub(crate) use self::history::now_millis;
+pub use self::watch::{Event, Watch, Changes};
+pub(crate) use self::watch::Watchers;
+pub use self::backup::restore;
+pub use self::stats::Stats;
+pub use self::compaction::CompactionPolicy;
+pub use self::vfs::{Vfs, VfsFile, DiskVfs};
+pub use self::memory_vfs::MemoryVfs;
+pub use self::export::{export, import, migrate, ExportFormat};
@@ -0,0 +1,41 @@
+use crate::kvs::{MergeOperator, Retention, CompactionPolicy, Vfs};
+use std::sync::Arc;
+
+///signature of a recovery progress callback: (bytes of the log replayed, bytes of the whole log,
+///bytes of a torn last record cut off), the last one is only set by the final call
+pub type ProgressFn = dyn Fn(u64, u64, u64) + Send + Sync;
+
+///settings applied when an engine is opened
+#[derive(Clone, Default)]
+pub struct Options {
+    ///operator used by `merge`, merging fails when it is not set
+    pub merge_operator: Option<MergeOperator>,
+    ///keep superseded versions for `history` and `get_at_bytes` until compaction prunes them,
+    ///only the latest version is kept when it is not set
+    pub retention: Option<Retention>,
+    ///how many logs replaced by compaction are kept for `changes`, none by default
+    pub retained_segments: usize,
+    ///when the store compacts on its own, after 2 MiB of outdated records by default
+    pub compaction: CompactionPolicy,
+    ///file system the store lives on, the local disk when it is not set
+    pub vfs: Option<Arc<dyn Vfs>>,
+    ///read records through a memory map of the data file instead of seeking a reader, which saves a
+    ///syscall per read. the map is read through `&self` and shared by the store and its snapshots on
+    ///any thread, reads of the store itself still take `&mut self`: sharing the store between readers
+    ///is not done yet. only on the local disk, opening fails when `vfs` is set too
+    pub mmap: bool,
+    ///bytes of recently read values `KvStore` keeps in memory, no cache when 0
+    pub cache_bytes: usize,
+    ///keep a 128-bit hash of every key in memory instead of the key itself, for key sets too large for
+    ///memory. scans in key order then read every key back from the data file. two keys may share a
+    ///hash: reads and writes compare the key of the record found, a key colliding with a stored one
+    ///reads as absent and writing it fails with `KeyCollisionError`. the hash is seeded at random when
+    ///the store opens, so a collision is as unlikely as chance makes it
+    pub hashed_keys: bool,
+    ///called while `KvStore` replays its log at open, every 64 MiB and once it is done. a last record
+    ///left incomplete by a crash during its append is cut off, the others have to parse
+    pub recovery_progress: Option<Arc<ProgressFn>>,
+    ///bytes of keys and values `SledKvsEngine` may copy into memory for a snapshot, a larger one fails
+    ///with `SnapshotTooLargeError`. no limit when it is not set
+    pub snapshot_bytes: Option<usize>,
+}
@@ -0,0 +1,97 @@
+use crate::err::Result;
+use std::path::{Path, PathBuf};
+use crate::kvs::backup::link_or_copy;
+use crate::kvs::vfs::{self, Vfs};
+use std::io::Write;
+
+///directory holding the logs replaced by compaction
+const SEGMENTS_DIR: &str = ".segments";
+///file holding the base of the live log
+const BASE_FILE: &str = "base";
+
+///a log replaced by compaction. records up to `base` were copied into it by the compaction
+///that created it, the ones after were appended in order up to `last`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Segment {
+    pub(crate) base: u64,
+    pub(crate) last: u64,
+    pub(crate) path: PathBuf,
+}
+
+///retained segments, oldest first
+pub(crate) fn list(vfs: &dyn Vfs, dir: &Path) -> Result<Vec<Segment>> {
+    let segments_dir = dir.join(SEGMENTS_DIR);
+    if !vfs.is_dir(&segments_dir) {
+        return Ok(vec![]);
+    }
+    let mut segments = vec![];
+    for path in vfs.read_dir(&segments_dir)? {
+        if path.extension() != Some("log".as_ref()) {
+            continue;
+        }
+        let name = path.file_stem().and_then(|name| name.to_str()).unwrap_or_default().to_owned();
+        if let Some((base, last)) = name.split_once('-') {
+            if let (Ok(base), Ok(last)) = (base.parse(), last.parse()) {
+                segments.push(Segment { base, last, path });
+            }
+        }
+    }
+    segments.sort_by_key(|segment| segment.last);
+    Ok(segments)
+}
+
+///the base of the live log, 0 until it is first compacted
+pub(crate) fn read_base(vfs: &dyn Vfs, dir: &Path) -> Result<u64> {
+    match vfs::read_to_string(vfs, &dir.join(SEGMENTS_DIR).join(BASE_FILE)) {
+        Ok(text) => Ok(text.trim().parse().unwrap_or_default()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e.into()),
+    }
+}
+
+///keep the log about to be replaced as a segment and record the base of its replacement,
+///called before the replacement is renamed into place so a crash never leaves a base that is too old
+pub(crate) fn archive(vfs: &dyn Vfs, dir: &Path, log: &Path, base: u64, last: u64, new_base: u64, retained: usize) -> Result<()> {
+    let segments_dir = dir.join(SEGMENTS_DIR);
+    vfs.create_dir_all(&segments_dir)?;
+    if retained > 0 {
+        let path = segments_dir.join(format!("{:020}-{:020}.log", base, last));
+        //left behind by a compaction that crashed before the rename
+        if vfs.is_file(&path) {
+            vfs.remove_file(&path)?;
+        }
+        vfs.hard_link(log, &path)?;
+    }
+    let segments = list(vfs, dir)?;
+    for segment in segments.iter().take(segments.len().saturating_sub(retained)) {
+        vfs.remove_file(&segment.path)?;
+    }
+    let tmp = segments_dir.join(format!("{}.tmp", BASE_FILE));
+    let mut file = vfs.create(&tmp)?;
+    file.write_all(new_base.to_string().as_bytes())?;
+    file.sync_all()?;
+    vfs.rename(&tmp, &segments_dir.join(BASE_FILE))?;
+    vfs.sync_dir(&segments_dir)?;
+    vfs.sync_dir(dir)?;
+    Ok(())
+}
+
+///link the retained segments and copy the base into the same layout under `dest`
+pub(crate) fn backup(vfs: &dyn Vfs, dir: &Path, dest: &Path) -> Result<()> {
+    let segments_dir = dir.join(SEGMENTS_DIR);
+    if !vfs.is_dir(&segments_dir) {
+        return Ok(());
+    }
+    let dest_dir = dest.join(SEGMENTS_DIR);
+    vfs.create_dir_all(&dest_dir)?;
+    for segment in list(vfs, dir)? {
+        if let Some(name) = segment.path.file_name() {
+            link_or_copy(vfs, &segment.path, &dest_dir.join(name))?;
+        }
+    }
+    //the base is rewritten by every compaction, so it is copied
+    if vfs.is_file(&segments_dir.join(BASE_FILE)) {
+        vfs::copy(vfs, &segments_dir.join(BASE_FILE), &dest_dir.join(BASE_FILE))?;
+    }
+    Ok(())
+}
@@ -1,43 +1,288 @@
-use crate::{KvsEngine,Result,Error};
-use std::path::PathBuf;
-use sled::{open,Db};
-///for benchmark
+use crate::{KvsEngine, Snapshot, Pairs, Version, Event, Watch, Changes, Stats, Result, Error, CompareAndSwapError, CompareAndSwapResult};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use crate::kvs::backup::prepare_dest;
+use crate::kvs::DiskVfs;
+use sled::{Db, IVec, Transactional, Tree};
+use sled::transaction::{abort, ConflictableTransactionResult, TransactionError, TransactionalTree};
+use crate::kvs::{MergeOperator, Options, Transaction};
+use crate::kvs::merge::add_to_counter;
+
+///the tree keeping a version for every key of the default tree
+const VERSIONS: &str = "versions";
+
+///for benchmark, every write is flushed before it returns so it survives the process being killed,
+///as writes to `KvStore` do
 pub struct SledKvsEngine{
-    db:Db
+    db:Db,
+    versions: Tree,
+    merge_operator: Option<MergeOperator>,
+    snapshot_bytes: Option<usize>,
+    reads: u64,
+    writes: u64,
 }
 impl SledKvsEngine{
     ///open
     pub fn open(path: impl Into<PathBuf> + Clone) -> Result<Self> {
-        let db:Db = sled::open(path.into()).unwrap();
+        Self::open_with(path, Options::default())
+    }
+
+    ///open with options, merges run in a transaction so they bump the version like any other write
+    pub fn open_with(path: impl Into<PathBuf> + Clone, options: Options) -> Result<Self> {
+        let db = open_db(&path.into())?;
+        let versions = db.open_tree(VERSIONS)?;
         Ok(SledKvsEngine{
-            db
+            db,
+            versions,
+            merge_operator: options.merge_operator,
+            snapshot_bytes: options.snapshot_bytes,
+            reads: 0,
+            writes: 0,
         })
     }
+
+    ///runs `f` over the data and the versions in one sled transaction, an abort is returned as is
+    fn transact<T>(&self, f: impl Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<T, Error>) -> Result<T> {
+        match (&*self.db, &self.versions).transaction(|(data, versions)| f(data, versions)) {
+            Ok(res) => Ok(res),
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
 }
 
 impl KvsEngine for SledKvsEngine{
 
-    fn set(&mut self, key: String, value: String) -> Result<()>{
-        self.db.insert(key.as_bytes(),value.as_bytes()).unwrap();
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()>{
+        self.writes += 1;
+        self.transact(|data, versions| put(data, versions, &key, Some(&value)))?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>>{
+        self.reads += 1;
+        Ok(self.db.get(key)?.map(|i_vec| i_vec.to_vec()))
+    }
+
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()>{
+        self.writes += 1;
+        self.transact(|data, versions| {
+            if data.get(&key)?.is_none() {
+                return abort(Error::KeyNotFoundError);
+            }
+            put(data, versions, &key, None)
+        })?;
+        self.db.flush()?;
         Ok(())
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>>{
-        return match self.db.get(&key.as_bytes()).unwrap(){
-            None=>{
-                Ok(None)
-            },
-            Some(i_vec)=>{
-                Ok(Some(String::from_utf8(i_vec.as_ref().to_owned())?))
+    fn compare_and_swap_bytes(&mut self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<CompareAndSwapResult<Vec<u8>>>{
+        self.writes += 1;
+        let res = self.transact(|data, versions| {
+            let current = data.get(&key)?;
+            if current.as_deref() != expected.as_deref() {
+                return Ok(Err(CompareAndSwapError { current: current.map(|i_vec| i_vec.to_vec()) }));
             }
+            put(data, versions, &key, new.as_deref())?;
+            Ok(Ok(()))
+        })?;
+        if res.is_ok() {
+            self.db.flush()?;
         }
+        Ok(res)
     }
 
-    fn remove(&mut self, key: String) -> Result<()>{
-        return match self.db.remove(&key.as_bytes()).unwrap(){
-            None=> Err(Error::KeyNotFoundError),
-            Some(_)=>Ok(())
+    fn increment_bytes(&mut self, key: Vec<u8>, delta: i64) -> Result<i64>{
+        self.writes += 1;
+        let value = self.transact(|data, versions| {
+            let value = match add_to_counter(data.get(&key)?.as_deref(), delta) {
+                Ok(value) => value,
+                Err(e) => return abort(e),
+            };
+            put(data, versions, &key, Some(value.to_string().as_bytes()))?;
+            Ok(value)
+        })?;
+        self.db.flush()?;
+        Ok(value)
+    }
+
+    fn merge_bytes(&mut self, key: Vec<u8>, operand: Vec<u8>) -> Result<()>{
+        let merge_operator = match &self.merge_operator {
+            Some(merge_operator) => merge_operator,
+            None => return Err(Error::NoMergeOperatorError),
+        };
+        self.transact(|data, versions| {
+            let merged = merge_operator.merge(&key, data.get(&key)?.as_deref(), &operand);
+            put(data, versions, &key, merged.as_deref())
+        })?;
+        self.writes += 1;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    ///sled iterators observe writes made while they run, so the view is copied out while
+    ///`&mut self` keeps writers away; its sequence is an id generated by sled. the copy takes as much
+    ///memory as the data, a database larger than `Options::snapshot_bytes` is refused when it is set.
+    ///`scan` reads the pairs in place instead
+    fn snapshot(&mut self) -> Result<Box<dyn Snapshot>>{
+        let sequence = self.db.generate_id()?;
+        let mut data = BTreeMap::new();
+        let mut bytes = 0;
+        for pair in self.db.iter() {
+            let (key, value) = pair?;
+            bytes += key.len() + value.len();
+            if self.snapshot_bytes.is_some_and(|limit| bytes > limit) {
+                return Err(Error::SnapshotTooLargeError);
+            }
+            data.insert(key.to_vec(), value.to_vec());
         }
+        Ok(Box::new(SledSnapshot { sequence, data }))
+    }
+
+    ///sled's own iterator, it sees the writes made while it runs, which the borrow keeps away
+    fn scan(&mut self) -> Result<Pairs<'_>>{
+        Ok(Box::new(self.db.iter().map(|pair| {
+            let (key, value) = pair?;
+            Ok((key.to_vec(), value.to_vec()))
+        })))
+    }
+
+    fn get_versioned(&mut self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)>{
+        self.reads += 1;
+        self.transact(|data, versions| {
+            let value = data.get(&key)?;
+            let version = version_of(value.as_ref(), versions.get(&key)?);
+            Ok((value.map(|i_vec| i_vec.to_vec()), version))
+        })
+    }
+
+    ///the check and the writes run in one sled transaction
+    fn commit(&mut self, txn: Transaction) -> Result<()>{
+        let reads = txn.reads();
+        let writes = txn.into_writes();
+        self.writes += writes.len() as u64;
+        self.transact(|data, versions| {
+            for (key, version) in reads.iter() {
+                if version_of(data.get(key)?.as_ref(), versions.get(key)?) != *version {
+                    return abort(Error::TransactionConflictError);
+                }
+            }
+            for (key, value) in writes.iter() {
+                put(data, versions, key, value.as_deref())?;
+            }
+            Ok(())
+        })?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    ///sled overwrites values in place, prior versions are not kept
+    fn history_bytes(&mut self, _key: Vec<u8>) -> Result<Vec<Version>> {
+        Err(Error::UnsupportedError)
+    }
+
+    ///sled's export walks a consistent view of every tree, imported into a fresh database at `dest`
+    fn backup(&mut self, dest: &Path) -> Result<()> {
+        prepare_dest(&DiskVfs, dest)?;
+        let backup = sled::open(dest)?;
+        backup.import(self.db.export());
+        backup.flush()?;
+        Ok(())
+    }
+
+    ///sled compacts on its own and does not expose its garbage, the size on disk is reported as live
+    fn stats(&mut self) -> Result<Stats> {
+        Ok(Stats {
+            keys: self.db.len() as u64,
+            live_bytes: self.db.size_on_disk()?,
+            reads: self.reads,
+            writes: self.writes,
+            ..Stats::default()
+        })
+    }
+
+    ///sled reclaims space on its own and offers no way to force it, its buffered writes are flushed
+    fn compact(&mut self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    ///sled keeps no log of its writes to replay
+    fn changes(&mut self, _since: u64) -> Result<Changes> {
+        Err(Error::UnsupportedError)
+    }
+
+    ///sled events carry no sequence number, each one takes a fresh id of the database instead
+    fn watch(&mut self, prefix: Vec<u8>) -> Result<Watch> {
+        let db = self.db.clone();
+        let subscriber = self.db.watch_prefix(prefix);
+        Ok(Box::new(subscriber.map(move |event| {
+            let sequence = db.generate_id().unwrap_or_default();
+            match event {
+                sled::Event::Insert { key, value } => Event::Set { sequence, key: key.to_vec(), value: value.to_vec() },
+                sled::Event::Remove { key } => Event::Remove { sequence, key: key.to_vec() },
+            }
+        })))
+    }
+}
+
+///a database dropped by this process keeps its lock until sled's flusher thread lets go of it,
+///shortly after, so opening it again is retried for up to a second
+fn open_db(path: &Path) -> Result<Db> {
+    for _ in 0..50 {
+        match sled::open(path) {
+            Err(sled::Error::Io(e)) if e.to_string().contains("could not acquire lock") => thread::sleep(Duration::from_millis(20)),
+            res => return Ok(res?),
+        }
+    }
+    Ok(sled::open(path)?)
+}
+
+///writes `value` under `key`, or removes it, and gives the key a fresh version taken from sled's ids.
+///a removed key keeps its version so that setting and removing it again is a change too
+fn put(data: &TransactionalTree, versions: &TransactionalTree, key: &[u8], value: Option<&[u8]>) -> ConflictableTransactionResult<(), Error> {
+    //0 is a key never written and 1 a key written before versions were kept
+    let version = data.generate_id()? + 2;
+    match value {
+        Some(value) => drop(data.insert(key, value)?),
+        None => drop(data.remove(key)?),
+    }
+    versions.insert(key, &version.to_be_bytes())?;
+    Ok(())
+}
+
+///the version of a key, 0 when it was never written
+fn version_of(value: Option<&IVec>, version: Option<IVec>) -> u64 {
+    match (value, version) {
+        (_, Some(version)) if version.len() == 8 => {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&version);
+            u64::from_be_bytes(bytes)
+        }
+        (None, _) => 0,
+        (Some(_), _) => 1,
+    }
+}
+
+struct SledSnapshot {
+    sequence: u64,
+    data: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Snapshot for SledSnapshot {
+    fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.data.get(&key).cloned())
+    }
+
+    fn iter(&mut self) -> Pairs<'_> {
+        Box::new(self.data.iter().map(|(key, value)| Ok((key.clone(), value.clone()))))
     }
 }
 #[cfg(test)]
@@ -45,49 +290,137 @@ mod tests {
     use tempfile::TempDir;
     use crate::err::{Result, Error};
     use crate::{SledKvsEngine, KvsEngine};
+    use crate::kvs::{MergeOperator, Options, Transaction, At, Event};
 
-    use std::path::Path;
+    #[test]
+    fn test_merge() -> Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
+        let options = Options { merge_operator: Some(MergeOperator::Append), ..Options::default() };
+        let mut db = SledKvsEngine::open_with(tmp.path(), options)?;
+        db.merge("key1".to_owned(), "a".to_owned())?;
+        db.merge("key1".to_owned(), "b".to_owned())?;
+        assert_eq!(db.get("key1".to_owned())?, Some("ab".to_owned()));
+        Ok(())
+    }
 
     #[test]
-    fn test_open() -> Result<()> {
+    fn test_snapshot() -> Result<()> {
         let tmp = TempDir::new().expect("create new dir err");
-        let db = SledKvsEngine::open(tmp.path())?;
+        let mut db = SledKvsEngine::open(tmp.path())?;
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        let mut snapshot = db.snapshot()?;
+        db.set("key1".to_owned(), "value2".to_owned())?;
+        db.set("key2".to_owned(), "value2".to_owned())?;
+        assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(snapshot.get("key2".to_owned())?, None);
+        assert_eq!(snapshot.iter().count(), 1);
+        assert!(db.snapshot()?.sequence() > snapshot.sequence());
         drop(db);
+
+        //the copy is bounded
+        let mut db = SledKvsEngine::open_with(tmp.path(), Options { snapshot_bytes: Some(24), ..Options::default() })?;
+        assert!(db.snapshot().is_ok());
+        db.set("key3".to_owned(), "value3".to_owned())?;
+        assert!(matches!(db.snapshot(), Err(Error::SnapshotTooLargeError)));
         Ok(())
     }
 
     #[test]
-    fn test_set() -> Result<()> {
+    fn test_transaction() -> Result<()> {
         let tmp = TempDir::new().expect("create new dir err");
         let mut db = SledKvsEngine::open(tmp.path())?;
-        db.set("key1".to_owned(), "value1".to_owned());
+        db.set("alice".to_owned(), "100".to_owned())?;
 
+        let mut txn = Transaction::new();
+        assert_eq!(txn.get(&mut db, b"alice".to_vec())?, Some(b"100".to_vec()));
+        assert_eq!(txn.get(&mut db, b"bob".to_vec())?, None);
+        txn.set(b"alice".to_vec(), b"70".to_vec());
+        txn.set(b"bob".to_vec(), b"30".to_vec());
+        db.commit(txn)?;
+        assert_eq!(db.get("bob".to_owned())?, Some("30".to_owned()));
+
+        let mut txn = Transaction::new();
+        txn.get(&mut db, b"bob".to_vec())?;
+        txn.remove(b"alice".to_vec());
+        db.set("bob".to_owned(), "31".to_owned())?;
+        assert!(matches!(db.commit(txn), Err(Error::TransactionConflictError)));
+        assert_eq!(db.get("alice".to_owned())?, Some("70".to_owned()));
         Ok(())
     }
 
     #[test]
-    fn test_get() -> Result<()> {
+    fn test_history_unsupported() -> Result<()> {
         let tmp = TempDir::new().expect("create new dir err");
         let mut db = SledKvsEngine::open(tmp.path())?;
         db.set("key1".to_owned(), "value1".to_owned())?;
-        db.set("key1".to_owned(), "value2".to_owned())?;
-        assert_eq!(db.get("key1".to_owned())?, Some("value2".to_owned()));
+        assert!(matches!(db.history("key1".to_owned()), Err(Error::UnsupportedError)));
+        assert!(matches!(db.get_at("key1".to_owned(), At::Sequence(1)), Err(Error::UnsupportedError)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_watch() -> Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
+        let mut db = SledKvsEngine::open(tmp.path())?;
+        let mut watch = db.watch(b"app.".to_vec())?;
+        db.set("app.name".to_owned(), "kvs".to_owned())?;
+        db.set("other".to_owned(), "value".to_owned())?;
+        db.remove("app.name".to_owned())?;
+
+        let first = watch.next().unwrap();
+        assert!(matches!(&first, Event::Set { key, value, .. } if key == b"app.name" && value == b"kvs"));
+        let second = watch.next().unwrap();
+        assert_eq!(second.key(), b"app.name");
+        assert!(matches!(second, Event::Remove { .. }));
+        assert!(second.sequence() > first.sequence());
         Ok(())
+    }
 
+    #[test]
+    fn test_changes_unsupported() -> Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
+        let mut db = SledKvsEngine::open(tmp.path())?;
+        assert!(matches!(db.changes(0), Err(Error::UnsupportedError)));
+        Ok(())
     }
 
     #[test]
-    fn test_remove() -> Result<()> {
+    fn test_backup() -> Result<()> {
         let tmp = TempDir::new().expect("create new dir err");
         let mut db = SledKvsEngine::open(tmp.path())?;
         db.set("key1".to_owned(), "value1".to_owned())?;
-        db.remove("key1".to_owned())?;
-
-        assert_eq!(db.get("key1".to_owned())?, None);
+        let backup = TempDir::new().expect("create new dir err");
+        db.backup(backup.path())?;
+        db.set("key2".to_owned(), "value2".to_owned())?;
 
+        let restored = TempDir::new().expect("create new dir err");
+        crate::restore(backup.path(), restored.path())?;
+        let mut copy = SledKvsEngine::open(restored.path())?;
+        assert_eq!(copy.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(copy.get("key2".to_owned())?, None);
         Ok(())
     }
 
+    #[test]
+    fn test_stats() -> Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
+        let mut db = SledKvsEngine::open(tmp.path())?;
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        db.set("key2".to_owned(), "value2".to_owned())?;
+        db.remove("key2".to_owned())?;
+        db.get("key1".to_owned())?;
+        let stats = db.stats()?;
+        assert_eq!((stats.keys, stats.reads, stats.writes), (1, 1, 3));
+        Ok(())
+    }
 
+    #[test]
+    fn test_compact() -> Result<()> {
+        let tmp = TempDir::new().expect("create new dir err");
+        let mut db = SledKvsEngine::open(tmp.path())?;
+        db.set("key1".to_owned(), "value1".to_owned())?;
+        db.compact()?;
+        assert_eq!(db.get("key1".to_owned())?, Some("value1".to_owned()));
+        Ok(())
+    }
 }
-
@@ -0,0 +1,34 @@
+use serde::{Serialize, Deserialize};
+use std::time::Duration;
+
+///counters and space usage of an engine, returned by `KvsEngine::stats`.
+///an engine reports 0 for what it does not track
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Stats {
+    ///keys holding a value
+    pub keys: u64,
+    ///bytes of the data still in use
+    pub live_bytes: u64,
+    ///bytes of outdated records that compaction would reclaim
+    pub dead_bytes: u64,
+    ///log files on disk, the live one and the retained ones
+    pub segments: u64,
+    ///compactions run since the engine was opened
+    pub compactions: u64,
+    ///how long the latest compaction took
+    pub last_compaction: Option<Duration>,
+    ///reads served since the engine was opened
+    pub reads: u64,
+    ///writes applied since the engine was opened
+    pub writes: u64,
+    ///reads answered by the value cache
+    pub cache_hits: u64,
+    ///reads that went past the value cache to the data file
+    pub cache_misses: u64,
+    ///estimated bytes of memory taken by the index of the keys
+    pub index_bytes: u64,
+    ///records the engine replayed when it was opened
+    pub recovered_records: u64,
+    ///how long opening took to replay the log
+    pub recovery: Option<Duration>,
+}
@@ -0,0 +1,93 @@
+use std::collections::BTreeMap;
+use serde::{Serialize, Deserialize};
+use crate::err::{Result, Error};
+use crate::KvsEngine;
+
+///reads and buffered writes of an optimistic transaction, the writes only reach the engine on commit.
+///every key read remembers the version it had, the commit fails with `TransactionConflictError`
+///if any of them changed in between.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Transaction {
+    reads: Vec<Read>,
+    writes: Vec<Write>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Read(#[serde(with = "crate::bytes")] Vec<u8>, u64);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Write(#[serde(with = "crate::bytes")] Vec<u8>, #[serde(with = "crate::bytes::option")] Option<Vec<u8>>);
+
+///where a transaction reads versioned values from and commits to: a local engine or a remote server
+pub trait TransactionTarget {
+    ///the value of `key` and its version, an absent key has one too
+    fn get_versioned(&mut self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)>;
+
+    ///apply the buffered writes, or fail with `TransactionConflictError`
+    fn commit(&mut self, txn: Transaction) -> Result<()>;
+}
+
+impl<E: KvsEngine + ?Sized> TransactionTarget for E {
+    fn get_versioned(&mut self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)> {
+        KvsEngine::get_versioned(self, key)
+    }
+
+    fn commit(&mut self, txn: Transaction) -> Result<()> {
+        KvsEngine::commit(self, txn)
+    }
+}
+
+impl Transaction {
+    ///start an empty transaction
+    pub fn new() -> Self {
+        Transaction::default()
+    }
+
+    ///read a key, seeing the writes buffered in this transaction
+    pub fn get<T: TransactionTarget + ?Sized>(&mut self, target: &mut T, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(Write(_, value)) = self.writes.iter().rev().find(|write| write.0 == key) {
+            return Ok(value.clone());
+        }
+        let (value, version) = target.get_versioned(key.clone())?;
+        if !self.reads.iter().any(|read| read.0 == key) {
+            self.reads.push(Read(key, version));
+        }
+        Ok(value)
+    }
+
+    ///buffer a write
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.push(Write(key, Some(value)));
+    }
+
+    ///buffer a removal, removing an absent key is not an error
+    pub fn remove(&mut self, key: Vec<u8>) {
+        self.writes.push(Write(key, None));
+    }
+
+    ///the keys read with the versions they had
+    pub fn reads(&self) -> Vec<(Vec<u8>, u64)> {
+        self.reads.iter().map(|read| (read.0.clone(), read.1)).collect()
+    }
+
+    ///the buffered writes, only the last one of each key, `None` removes the key
+    pub fn into_writes(self) -> BTreeMap<Vec<u8>, Option<Vec<u8>>> {
+        self.writes.into_iter().map(|write| (write.0, write.1)).collect()
+    }
+}
+
+///run `f` in a fresh transaction and commit it, running again up to `retries` times on conflict
+pub fn run_transaction<T, R, F>(target: &mut T, retries: usize, mut f: F) -> Result<R>
+    where T: TransactionTarget + ?Sized,
+          F: FnMut(&mut Transaction, &mut T) -> Result<R> {
+    let mut attempt = 0;
+    loop {
+        let mut txn = Transaction::new();
+        let res = f(&mut txn, target)?;
+        match target.commit(txn) {
+            Err(Error::TransactionConflictError) if attempt < retries => attempt += 1,
+            Err(e) => return Err(e),
+            Ok(()) => return Ok(res),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+///file operations `KvStore` goes through, so that it can run on a simulated disk
+pub trait Vfs: Send + Sync {
+    ///open a file to read and append to, it is created when missing
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn VfsFile>>;
+    ///create a file to read and append to, failing when it exists
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn VfsFile>>;
+    ///create a file to write, an existing one is truncated
+    fn create(&self, path: &Path) -> io::Result<Box<dyn VfsFile>>;
+    ///open an existing file to read
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn VfsFile>>;
+    ///paths of the entries of a directory
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+    ///create a directory and its missing parents
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+    ///whether a file exists at `path`
+    fn is_file(&self, path: &Path) -> bool;
+    ///whether a directory exists at `path`
+    fn is_dir(&self, path: &Path) -> bool;
+    ///remove a file
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    ///replace `to` with `from`
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    ///give the file at `from` the new name `to` as well
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()>;
+    ///make the entries created, renamed or removed in `dir` durable
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+}
+
+///an open file of a `Vfs`
+pub trait VfsFile: Read + Write + Seek + Send {
+    ///make the written data durable
+    fn sync_all(&self) -> io::Result<()>;
+    ///length of the file in bytes
+    fn size(&self) -> io::Result<u64>;
+    ///truncate or extend the file to `len` bytes
+    fn set_len(&self, len: u64) -> io::Result<()>;
+    ///another handle to the same file
+    fn try_clone(&self) -> io::Result<Box<dyn VfsFile>>;
+}
+
+///the local file system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiskVfs;
+
+impl Vfs for DiskVfs {
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(OpenOptions::new().read(true).append(true).create(true).open(path)?))
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(OpenOptions::new().read(true).append(true).create_new(true).open(path)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(dir)?.map(|entry| entry.map(|entry| entry.path())).collect()
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::hard_link(from, to)
+    }
+
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+}
+
+impl VfsFile for File {
+    fn sync_all(&self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<dyn VfsFile>> {
+        Ok(Box::new(File::try_clone(self)?))
+    }
+}
+
+///copy the content of a file into a new one
+pub(crate) fn copy(vfs: &dyn Vfs, from: &Path, to: &Path) -> io::Result<u64> {
+    let mut target = vfs.create(to)?;
+    let copied = io::copy(&mut vfs.open_read(from)?, &mut target)?;
+    target.sync_all()?;
+    Ok(copied)
+}
+
+pub(crate) fn read_to_string(vfs: &dyn Vfs, path: &Path) -> io::Result<String> {
+    let mut text = String::new();
+    vfs.open_read(path)?.read_to_string(&mut text)?;
+    Ok(text)
+}
@@ -0,0 +1,107 @@
+use serde::{Serialize, Deserialize};
+use std::sync::mpsc::{channel, Sender};
+
+///a change to a watched key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Event {
+    ///the key now holds `value`, merges are reported with the merged value
+    Set {
+        ///sequence number of the write
+        sequence: u64,
+        ///the changed key
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+        ///the new value
+        #[serde(with = "crate::bytes")]
+        value: Vec<u8>,
+    },
+    ///the key was removed
+    Remove {
+        ///sequence number of the write
+        sequence: u64,
+        ///the removed key
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+    },
+    ///a merge operand was recorded, only change feeds report it, watches see the merged value as `Set`
+    Merge {
+        ///sequence number of the write
+        sequence: u64,
+        ///the merged key
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+        ///the operand given to `merge`
+        #[serde(with = "crate::bytes")]
+        operand: Vec<u8>,
+    },
+}
+
+impl Event {
+    ///sequence number of the write
+    pub fn sequence(&self) -> u64 {
+        match self {
+            Event::Set { sequence, .. } | Event::Remove { sequence, .. } | Event::Merge { sequence, .. } => *sequence,
+        }
+    }
+
+    ///the changed key
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Event::Set { key, .. } | Event::Remove { key, .. } | Event::Merge { key, .. } => key,
+        }
+    }
+}
+
+///events of the keys under a prefix in the order they are written, blocking until the next one arrives
+pub type Watch = Box<dyn Iterator<Item=Event> + Send>;
+
+///every mutation after a sequence number in order, the stored ones first and then the live ones as they happen
+pub type Changes = Box<dyn Iterator<Item=crate::Result<Event>> + Send>;
+
+///the open watches of an engine that publishes its own writes
+#[derive(Default)]
+pub(crate) struct Watchers {
+    senders: Vec<(Vec<u8>, Sender<Event>)>,
+    ///change feeds, they get merge operands rather than merged values
+    feeds: Vec<Sender<Event>>,
+}
+
+impl Watchers {
+    pub(crate) fn add(&mut self, prefix: Vec<u8>) -> Watch {
+        let (sender, receiver) = channel();
+        self.senders.push((prefix, sender));
+        Box::new(receiver.into_iter())
+    }
+
+    ///a feed of every write, used to follow a change feed once the stored records are read
+    pub(crate) fn add_feed(&mut self) -> Watch {
+        let (sender, receiver) = channel();
+        self.feeds.push(sender);
+        Box::new(receiver.into_iter())
+    }
+
+    ///whether an event for `key` would be delivered, so callers can skip building it
+    pub(crate) fn is_watching(&self, key: &[u8]) -> bool {
+        !self.feeds.is_empty() || self.senders.iter().any(|(prefix, _)| key.starts_with(prefix))
+    }
+
+    ///deliver the event to every matching watch and feed, the ones whose receiver was dropped are forgotten
+    pub(crate) fn notify(&mut self, event: Event) {
+        self.feeds.retain(|sender| sender.send(event.clone()).is_ok());
+        self.senders.retain(|(prefix, sender)| {
+            !event.key().starts_with(prefix) || sender.send(event.clone()).is_ok()
+        });
+    }
+
+    ///feeds get the operand of a merge and watches the merged value, `None` when it merged into nothing
+    pub(crate) fn notify_merge(&mut self, sequence: u64, key: Vec<u8>, operand: Vec<u8>, value: Option<Vec<u8>>) {
+        let merge = Event::Merge { sequence, key: key.clone(), operand };
+        self.feeds.retain(|sender| sender.send(merge.clone()).is_ok());
+        if let Some(value) = value {
+            let set = Event::Set { sequence, key, value };
+            self.senders.retain(|(prefix, sender)| {
+                !set.key().starts_with(prefix) || sender.send(set.clone()).is_ok()
+            });
+        }
+    }
+}
@@ -1,17 +1,166 @@
 #[deny(missing_docs)]
 mod kvs;
 mod err;
+mod bytes;
+mod client;
 pub mod utils;
+pub mod metrics;
 
 pub use crate::kvs::Database as KvStore;
 pub use crate::kvs::SledKvsEngine;
-pub use err::{Result,Error};
+pub use crate::kvs::MemoryKvsEngine;
+pub use crate::kvs::{MergeOperator, MergeFn, Options, ProgressFn, CompactionPolicy};
+pub use crate::kvs::{Vfs, VfsFile, DiskVfs, MemoryVfs};
+pub use crate::kvs::{Transaction, TransactionTarget, run_transaction};
+pub use crate::kvs::{Retention, At, Version};
+pub use crate::kvs::{Event, Watch, Changes};
+pub use crate::kvs::restore;
+pub use crate::kvs::{export, import, migrate, ExportFormat};
+pub use crate::kvs::inspect;
+pub use crate::kvs::Stats;
+pub use client::KvsClient;
+use std::path::Path;
+pub use err::{Result, Error, CompareAndSwapError, CompareAndSwapResult};
 
 
+///a key-value engine storing arbitrary bytes, the `String` methods are conveniences over the `*_bytes` ones
 pub trait KvsEngine{
-    fn set(&mut self, key: String, value: String) -> Result<()>;
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
 
-    fn get(&mut self, key: String) -> Result<Option<String>>;
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
 
-    fn remove(&mut self, key: String) -> Result<()>;
-}
\ No newline at end of file
+    fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()>;
+
+    ///replace the value of `key` with `new` only if the stored value equals `expected`.
+    ///`None` stands for an absent key, so `new: None` removes the key.
+    ///on conflict the current value is returned in the inner error.
+    fn compare_and_swap_bytes(&mut self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<CompareAndSwapResult<Vec<u8>>>;
+
+    ///atomically add `delta` to the integer stored at `key` (an absent key counts as 0) and return the new value
+    fn increment_bytes(&mut self, key: Vec<u8>, delta: i64) -> Result<i64>;
+
+    ///record `operand` to be combined with the stored value by the merge operator registered at open
+    fn merge_bytes(&mut self, key: Vec<u8>, operand: Vec<u8>) -> Result<()>;
+
+    ///a read-only view frozen at the current sequence number, writes made afterwards are not visible in it.
+    ///its cost depends on the engine: `KvStore` and `MemoryKvsEngine` share their index with it and the
+    ///first write afterwards copies the index, `SledKvsEngine` copies every pair into memory, see
+    ///`Options::snapshot_bytes`. `scan` reads the pairs without a snapshot
+    fn snapshot(&mut self) -> Result<Box<dyn Snapshot>>;
+
+    ///every live pair in key order, read as the iteration goes while it borrows the engine. the default
+    ///copies them out of a snapshot, engines reading them in place override it
+    fn scan(&mut self) -> Result<Pairs<'_>> {
+        let pairs: Vec<_> = self.snapshot()?.iter().collect();
+        Ok(Box::new(pairs.into_iter()))
+    }
+
+    ///the value of `key` and its version, which changes with every write of the key, its removal included;
+    ///used by `Transaction` to detect conflicts
+    fn get_versioned(&mut self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)>;
+
+    ///apply the writes of `txn` together, or fail with `TransactionConflictError` if a key it read changed version
+    fn commit(&mut self, txn: Transaction) -> Result<()>;
+
+    ///every version of `key` still retained, oldest first
+    fn history_bytes(&mut self, key: Vec<u8>) -> Result<Vec<Version>>;
+
+    ///follow the writes to every key starting with `prefix` (a single key or a key prefix) from now on
+    fn watch(&mut self, prefix: Vec<u8>) -> Result<Watch>;
+
+    ///every mutation with a sequence number above `since` in order, followed by the live ones,
+    ///fails with `SequenceUnavailableError` when some of them are no longer retained
+    fn changes(&mut self, since: u64) -> Result<Changes>;
+
+    ///write a consistent copy of the store into `dest`, which must be empty or absent, while it keeps serving.
+    ///the copy can be opened directly or brought back with `restore`
+    fn backup(&mut self, dest: &Path) -> Result<()>;
+
+    ///space usage and activity counters of the engine
+    fn stats(&mut self) -> Result<Stats>;
+
+    ///reclaim the space of outdated records now, whatever the compaction policy says
+    fn compact(&mut self) -> Result<()>;
+
+    ///the value `key` had at `at`, `None` if it was absent or its version is no longer retained
+    fn get_at_bytes(&mut self, key: Vec<u8>, at: At) -> Result<Option<Vec<u8>>> {
+        let version = self.history_bytes(key)?.into_iter()
+            .take_while(|version| version.written_by(at))
+            .last();
+        Ok(version.and_then(|version| version.value))
+    }
+
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    ///fails with `InternalError` if the stored value is not valid UTF-8
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            None => Ok(None),
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+        }
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    fn compare_and_swap(&mut self, key: String, expected: Option<String>, new: Option<String>) -> Result<CompareAndSwapResult> {
+        match self.compare_and_swap_bytes(key.into_bytes(), expected.map(String::into_bytes), new.map(String::into_bytes))? {
+            Ok(()) => Ok(Ok(())),
+            Err(conflict) => {
+                let current = match conflict.current {
+                    None => None,
+                    Some(value) => Some(String::from_utf8(value)?),
+                };
+                Ok(Err(CompareAndSwapError { current }))
+            }
+        }
+    }
+
+    ///store the mapping only if `key` is absent, otherwise return the current value in the inner error
+    fn set_if_absent(&mut self, key: String, value: String) -> Result<CompareAndSwapResult> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.increment_bytes(key.into_bytes(), delta)
+    }
+
+    fn merge(&mut self, key: String, operand: String) -> Result<()> {
+        self.merge_bytes(key.into_bytes(), operand.into_bytes())
+    }
+
+    fn history(&mut self, key: String) -> Result<Vec<Version>> {
+        self.history_bytes(key.into_bytes())
+    }
+
+    fn get_at(&mut self, key: String, at: At) -> Result<Option<String>> {
+        match self.get_at_bytes(key.into_bytes(), at)? {
+            None => Ok(None),
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+        }
+    }
+}
+
+///key-value pairs yielded in key order
+pub type Pairs<'a> = Box<dyn Iterator<Item=Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+///read-only view of an engine returned by `KvsEngine::snapshot`, it can outlive the borrow of the engine
+pub trait Snapshot: Send {
+    ///sequence number the view is frozen at
+    fn sequence(&self) -> u64;
+
+    fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    ///every live key-value pair in key order
+    fn iter(&mut self) -> Pairs<'_>;
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            None => Ok(None),
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+        }
+    }
+}
@@ -0,0 +1,158 @@
+//!request metrics of `kvs-server`, rendered in the Prometheus text exposition format
+use crate::kvs::Stats;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+///upper bounds in seconds of the latency histogram buckets
+const BUCKETS: [f64; 9] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+///how long the metrics listener waits on a connection before it gives up on it
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Default)]
+struct CommandMetrics {
+    requests: u64,
+    errors: u64,
+    ///requests per bucket, not cumulative, the last one counts the slower ones
+    buckets: [u64; BUCKETS.len() + 1],
+    seconds: f64,
+}
+
+///counters shared by the connection loop and the metrics listener
+#[derive(Default)]
+pub struct Metrics {
+    commands: Mutex<BTreeMap<&'static str, CommandMetrics>>,
+    open_connections: AtomicI64,
+}
+
+impl Metrics {
+    ///count a request of `command` that took `duration`, `failed` when it was answered with an error
+    pub fn record(&self, command: &'static str, duration: Duration, failed: bool) {
+        let mut commands = self.commands.lock().unwrap();
+        let metrics = commands.entry(command).or_default();
+        metrics.requests += 1;
+        if failed {
+            metrics.errors += 1;
+        }
+        let seconds = duration.as_secs_f64();
+        let bucket = BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(BUCKETS.len());
+        metrics.buckets[bucket] += 1;
+        metrics.seconds += seconds;
+    }
+
+    pub fn connection_opened(&self) {
+        self.open_connections.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn connection_closed(&self) {
+        self.open_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    ///every metric in the text exposition format, followed by the engine statistics when given
+    pub fn render(&self, stats: Option<&Stats>) -> String {
+        let mut out = String::new();
+        let commands = self.commands.lock().unwrap();
+        out.push_str("# HELP kvs_requests_total Requests handled per command.\n# TYPE kvs_requests_total counter\n");
+        for (command, metrics) in commands.iter() {
+            let _ = writeln!(out, "kvs_requests_total{{command=\"{}\"}} {}", command, metrics.requests);
+        }
+        out.push_str("# HELP kvs_errors_total Requests answered with an error per command.\n# TYPE kvs_errors_total counter\n");
+        for (command, metrics) in commands.iter() {
+            let _ = writeln!(out, "kvs_errors_total{{command=\"{}\"}} {}", command, metrics.errors);
+        }
+        out.push_str("# HELP kvs_request_duration_seconds Time to handle a request per command.\n# TYPE kvs_request_duration_seconds histogram\n");
+        for (command, metrics) in commands.iter() {
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(metrics.buckets.iter()) {
+                cumulative += count;
+                let _ = writeln!(out, "kvs_request_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}", command, bound, cumulative);
+            }
+            let _ = writeln!(out, "kvs_request_duration_seconds_bucket{{command=\"{}\",le=\"+Inf\"}} {}", command, metrics.requests);
+            let _ = writeln!(out, "kvs_request_duration_seconds_sum{{command=\"{}\"}} {}", command, metrics.seconds);
+            let _ = writeln!(out, "kvs_request_duration_seconds_count{{command=\"{}\"}} {}", command, metrics.requests);
+        }
+        out.push_str("# HELP kvs_open_connections Client connections being served.\n# TYPE kvs_open_connections gauge\n");
+        let _ = writeln!(out, "kvs_open_connections {}", self.open_connections.load(Ordering::SeqCst));
+        if let Some(stats) = stats {
+            let last_compaction = stats.last_compaction.map_or(0.0, |duration| duration.as_secs_f64());
+            let recovery = stats.recovery.map_or(0.0, |duration| duration.as_secs_f64());
+            let engine: [(&str, &str, &str, f64); 13] = [
+                ("kvs_keys", "gauge", "Keys holding a value.", stats.keys as f64),
+                ("kvs_live_bytes", "gauge", "Bytes of the data still in use.", stats.live_bytes as f64),
+                ("kvs_dead_bytes", "gauge", "Bytes compaction would reclaim.", stats.dead_bytes as f64),
+                ("kvs_segments", "gauge", "Log files on disk.", stats.segments as f64),
+                ("kvs_compactions_total", "counter", "Compactions run.", stats.compactions as f64),
+                ("kvs_last_compaction_seconds", "gauge", "Duration of the latest compaction.", last_compaction),
+                ("kvs_engine_reads_total", "counter", "Reads served by the engine.", stats.reads as f64),
+                ("kvs_engine_writes_total", "counter", "Writes applied by the engine.", stats.writes as f64),
+                ("kvs_cache_hits_total", "counter", "Reads answered by the value cache.", stats.cache_hits as f64),
+                ("kvs_cache_misses_total", "counter", "Reads that missed the value cache.", stats.cache_misses as f64),
+                ("kvs_index_bytes", "gauge", "Estimated memory taken by the index of the keys.", stats.index_bytes as f64),
+                ("kvs_recovered_records", "gauge", "Records replayed when the engine was opened.", stats.recovered_records as f64),
+                ("kvs_recovery_seconds", "gauge", "Time taken to replay the log at open.", recovery),
+            ];
+            for (name, kind, help, value) in engine.iter() {
+                let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+            }
+        }
+        out
+    }
+}
+
+///answer every HTTP request on `listener` with the output of `render`, one request per connection,
+///a connection silent for two seconds is dropped
+pub fn serve_metrics(listener: TcpListener, render: impl Fn() -> String) {
+    for stream in listener.incoming().flatten() {
+        if stream.set_read_timeout(Some(SCRAPE_TIMEOUT)).is_err() || stream.set_write_timeout(Some(SCRAPE_TIMEOUT)).is_err() {
+            continue;
+        }
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).is_err() {
+            continue;
+        }
+        //the headers are not needed, they are read so the client sees its request consumed
+        let mut header = String::new();
+        while reader.read_line(&mut header).is_ok_and(|n| n > 0) && !header.trim().is_empty() {
+            header.clear();
+        }
+        let response = if request_line.starts_with("GET /metrics ") || request_line.starts_with("GET / ") {
+            let body = render();
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+        } else {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_owned()
+        };
+        let _ = (&stream).write_all(response.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::Metrics;
+    use crate::kvs::Stats;
+    use std::time::Duration;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.record("get", Duration::from_micros(50), false);
+        metrics.record("get", Duration::from_millis(3), true);
+        metrics.record("get", Duration::from_secs(2), false);
+        metrics.connection_opened();
+        let stats = Stats { keys: 3, ..Stats::default() };
+        let text = metrics.render(Some(&stats));
+        assert!(text.contains("kvs_requests_total{command=\"get\"} 3\n"));
+        assert!(text.contains("kvs_errors_total{command=\"get\"} 1\n"));
+        assert!(text.contains("kvs_request_duration_seconds_bucket{command=\"get\",le=\"0.0001\"} 1\n"));
+        assert!(text.contains("kvs_request_duration_seconds_bucket{command=\"get\",le=\"0.005\"} 2\n"));
+        assert!(text.contains("kvs_request_duration_seconds_bucket{command=\"get\",le=\"1\"} 2\n"));
+        assert!(text.contains("kvs_request_duration_seconds_bucket{command=\"get\",le=\"+Inf\"} 3\n"));
+        assert!(text.contains("kvs_open_connections 1\n"));
+        assert!(text.contains("# TYPE kvs_keys gauge\nkvs_keys 3\n"));
+    }
+}
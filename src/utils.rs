@@ -1,19 +1,83 @@
 use std::net::{SocketAddr, AddrParseError};
 use std::str::FromStr;
 use serde::{Serialize,Deserialize};
+use crate::kvs::{Transaction, At, Version, Event, Stats};
 pub fn parse_addr(addr: &str) -> std::result::Result<SocketAddr, AddrParseError> {
     SocketAddr::from_str(addr)
 }
 
-///message between client and server
+///message between client and server, keys and values are bytes
 #[derive(Serialize,Deserialize,Debug)]
 pub enum Command{
-    Set(String,String),
-    Get(String),
-    Remove(String),
+    Set(#[serde(with = "crate::bytes")] Vec<u8>, #[serde(with = "crate::bytes")] Vec<u8>),
+    Get(#[serde(with = "crate::bytes")] Vec<u8>),
+    Remove(#[serde(with = "crate::bytes")] Vec<u8>),
+    ///compare and swap (key, expected, new)
+    Cas(
+        #[serde(with = "crate::bytes")] Vec<u8>,
+        #[serde(with = "crate::bytes::option")] Option<Vec<u8>>,
+        #[serde(with = "crate::bytes::option")] Option<Vec<u8>>,
+    ),
+    SetIfAbsent(#[serde(with = "crate::bytes")] Vec<u8>, #[serde(with = "crate::bytes")] Vec<u8>),
+    ///increment (key, delta), answered with the new value
+    Incr(#[serde(with = "crate::bytes")] Vec<u8>, i64),
+    ///read inside a transaction, answered with `Versioned`
+    TxnGet(#[serde(with = "crate::bytes")] Vec<u8>),
+    ///commit the reads and writes a client collected, answered with `Ok` or `TxnConflict`
+    TxnCommit(Transaction),
+    ///read a key as it was at a sequence number or time, answered with `Ok`
+    GetAt(#[serde(with = "crate::bytes")] Vec<u8>, At),
+    ///list the retained versions of a key, answered with `Versions`
+    History(#[serde(with = "crate::bytes")] Vec<u8>),
+    ///keep the connection open and receive an `Event` for every write under the prefix
+    Watch(#[serde(with = "crate::bytes")] Vec<u8>),
+    ///keep the connection open and receive an `Event` for every mutation after the sequence number
+    Changes(u64),
+    ///write a backup into a directory on the server, answered with `Ok`
+    Backup(String),
+    ///ask for the engine statistics, answered with `EngineStats`
+    Stats,
+    ///compact the engine now, answered with `Ok`
+    Compact,
     Ping,
     Pong,
-    Ok(Option<String>),
-    Err
+    Ok(#[serde(with = "crate::bytes::option")] Option<Vec<u8>>),
+    ///a conditional write was rejected, carrying the current value
+    Conflict(#[serde(with = "crate::bytes::option")] Option<Vec<u8>>),
+    ///value with its version
+    Versioned(#[serde(with = "crate::bytes::option")] Option<Vec<u8>>, u64),
+    ///the transaction was rejected because a key it read changed, it can be retried
+    TxnConflict,
+    ///retained versions of a key, oldest first
+    Versions(Vec<Version>),
+    ///a write seen by a watch
+    Event(Event),
+    ///statistics of the engine
+    EngineStats(Stats),
+    Err(String)
 }
 
+impl Command {
+    ///name of the command, used to label metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Set(..) => "set",
+            Command::Get(..) => "get",
+            Command::Remove(..) => "remove",
+            Command::Cas(..) => "cas",
+            Command::SetIfAbsent(..) => "setnx",
+            Command::Incr(..) => "incr",
+            Command::TxnGet(..) => "txn_get",
+            Command::TxnCommit(..) => "txn_commit",
+            Command::GetAt(..) => "get_at",
+            Command::History(..) => "history",
+            Command::Watch(..) => "watch",
+            Command::Changes(..) => "changes",
+            Command::Backup(..) => "backup",
+            Command::Stats => "stats",
+            Command::Compact => "compact",
+            Command::Ping => "ping",
+            _ => "other",
+        }
+    }
+}
@@ -1,7 +1,11 @@
 use assert_cmd::prelude::*;
+use kvs::utils::Command as Request;
+use kvs::{run_transaction, At, KvStore, KvsClient, KvsEngine};
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -20,28 +24,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +56,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +95,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +127,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +138,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +149,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +161,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("fail to wait server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,15 +183,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("fail to wait server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,15 +203,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("fail to wait server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -217,19 +224,21 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        //the server is restarted on the same directory, sled holds a lock on it until it exits
+        child.wait().expect("fail to wait server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +246,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +254,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +262,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +270,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +278,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +286,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +294,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -298,26 +307,28 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        //the server is restarted on the same directory, sled holds a lock on it until it exits
+        child.wait().expect("fail to wait server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -334,4 +345,710 @@ fn cli_access_server_kvs_engine() {
 #[test]
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
-}
\ No newline at end of file
+}
+#[test]
+fn cli_conditional_write() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["setnx", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["setnx", "key1", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("value1"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key1", "--expected", "value0", "--new", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("value1"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key1", "--expected", "value1", "--new", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("fail to wait server");
+}
+
+#[test]
+fn cli_increment() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "counter", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "counter", "-5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("-4\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["incr", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not a valid integer"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("fail to wait server");
+}
+
+#[test]
+fn cli_binary_formats() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4008";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "00ff", "deadbeef", "--input-format", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "AP8=", "--input-format", "base64", "--output-format", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("deadbeef\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "00ff", "--input-format", "hex", "--output-format", "base64", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("3q2+7w==\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "zz", "--input-format", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("fail to wait server");
+}
+
+#[test]
+fn remote_transaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4009";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::new(addr.parse().unwrap());
+    client
+        .request(&Request::Set(b"alice".to_vec(), b"100".to_vec()))
+        .unwrap();
+
+    // move 30 from alice to bob, losing the first attempt to a concurrent writer
+    let mut attempts = 0;
+    run_transaction(&mut client, 3, |txn, client| {
+        attempts += 1;
+        let alice = txn.get(client, b"alice".to_vec())?.unwrap();
+        let bob = txn.get(client, b"bob".to_vec())?.unwrap_or_else(|| b"0".to_vec());
+        if attempts == 1 {
+            client.request(&Request::Set(b"alice".to_vec(), b"90".to_vec()))?;
+        }
+        let parse = |v: Vec<u8>| String::from_utf8(v).unwrap().parse::<i64>().unwrap();
+        txn.set(b"alice".to_vec(), (parse(alice) - 30).to_string().into_bytes());
+        txn.set(b"bob".to_vec(), (parse(bob) + 30).to_string().into_bytes());
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(attempts, 2);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "alice", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("60\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "bob", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("30\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("fail to wait server");
+}
+
+#[test]
+fn cli_history() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4010";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--retain-versions", "5"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for args in [&["set", "key1", "value1"][..], &["set", "key1", "value2"], &["rm", "key1"]] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["history", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<Vec<&str>> = stdout.lines().map(|line| line.split('\t').collect()).collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!((lines[0][0], lines[0][2]), ("1", "value1"));
+    assert_eq!((lines[1][0], lines[1][2]), ("2", "value2"));
+    assert_eq!((lines[2][0], lines[2][2]), ("3", "(removed)"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get-at", "key1", "--sequence", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get-at", "key1", "--timestamp", lines[1][1], "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get-at", "key1", "--sequence", "3", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    let client = KvsClient::new(addr.parse().unwrap());
+    assert_eq!(client.get_at(b"key1".to_vec(), At::Sequence(2)).unwrap(), Some(b"value2".to_vec()));
+    assert_eq!(client.history(b"key1".to_vec()).unwrap().len(), 3);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("fail to wait server");
+}
+
+#[test]
+fn cli_watch() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4011";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut watcher = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["watch", "--prefix", "app.", "--addr", addr])
+        .current_dir(&temp_dir)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    for args in [&["set", "app.name", "kvs"][..], &["set", "other", "value"], &["rm", "app.name"]] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    let mut lines = BufReader::new(watcher.stdout.take().unwrap()).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "1\tset\tapp.name\tkvs");
+    assert_eq!(lines.next().unwrap().unwrap(), "3\tremove\tapp.name");
+
+    // a library client sees the events too
+    let mut events = KvsClient::new(addr.parse().unwrap()).watch(b"app.".to_vec()).unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "app.port", "4000", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert_eq!(events.next().unwrap().unwrap().sequence(), 4);
+
+    watcher.kill().expect("watcher exited before killed");
+    watcher.wait().expect("fail to wait watcher");
+    child.kill().expect("server exited before killed");
+    child.wait().expect("fail to wait server");
+}
+
+#[test]
+fn remote_changes() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4012";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--retained-segments", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr.parse().unwrap());
+    client.request(&Request::Set(b"key1".to_vec(), b"value1".to_vec())).unwrap();
+    client.request(&Request::Remove(b"key1".to_vec())).unwrap();
+
+    // resume after the first mutation, then follow the live ones
+    let mut changes = client.changes(1).unwrap();
+    assert_eq!(changes.next().unwrap().unwrap().sequence(), 2);
+    client.request(&Request::Set(b"key2".to_vec(), b"value2".to_vec())).unwrap();
+    assert_eq!(changes.next().unwrap().unwrap().sequence(), 3);
+
+    let mut tail = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["changes", "--since", "0", "--addr", addr])
+        .current_dir(&temp_dir)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(tail.stdout.take().unwrap()).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "1\tset\tkey1\tvalue1");
+    assert_eq!(lines.next().unwrap().unwrap(), "2\tremove\tkey1");
+    assert_eq!(lines.next().unwrap().unwrap(), "3\tset\tkey2\tvalue2");
+
+    tail.kill().expect("client exited before killed");
+    tail.wait().expect("fail to wait client");
+    child.kill().expect("server exited before killed");
+    child.wait().expect("fail to wait server");
+}
+
+#[test]
+fn cli_backup_restore() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4013";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr.parse().unwrap());
+    client.request(&Request::Set(b"key1".to_vec(), b"value1".to_vec())).unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let backup = backup_dir.path().join("backup");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["backup", backup.to_str().unwrap(), "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["backup", backup.to_str().unwrap(), "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not empty"));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("fail to wait server");
+
+    let restored = backup_dir.path().join("restored");
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(["restore", backup.to_str().unwrap(), "--dir", restored.to_str().unwrap()])
+        .assert()
+        .success();
+    let mut store = KvStore::open(restored).unwrap();
+    assert_eq!(store.get("key1".to_owned()).unwrap(), Some("value1".to_owned()));
+}
+
+#[test]
+fn tool_export_import_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let source = temp_dir.path().join("source");
+    fs::create_dir(&source).unwrap();
+    let mut store = KvStore::open(&source).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(["export", "--dir", source.to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout("{\"key\":\"key1\",\"value\":\"value1\"}\n{\"key\":\"key2\",\"value\":\"value2\"}\n");
+
+    let dump = temp_dir.path().join("dump.bin");
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(["export", "--dir", source.to_str().unwrap(), "--format", "binary", "--output", dump.to_str().unwrap()])
+        .assert()
+        .success();
+    let imported = temp_dir.path().join("imported");
+    fs::create_dir(&imported).unwrap();
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(["import", "--dir", imported.to_str().unwrap(), "--engine", "sled", "--format", "binary", "--input", dump.to_str().unwrap()])
+        .assert()
+        .success()
+        .stderr(contains("imported 2 pairs"));
+
+    // the sled directory cannot be read as kvs, migrating it is the way over
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(["export", "--dir", imported.to_str().unwrap(), "--engine", "kvs"])
+        .assert()
+        .failure();
+    let migrated = temp_dir.path().join("migrated");
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(["migrate", "--from", "sled", "--to", "kvs", "--dir", imported.to_str().unwrap(), "--dest", migrated.to_str().unwrap()])
+        .assert()
+        .success()
+        .stderr(contains("migrated 2 pairs"));
+    let mut store = KvStore::open(&migrated).unwrap();
+    assert_eq!(store.get("key2".to_owned()).unwrap(), Some("value2".to_owned()));
+}
+
+#[test]
+fn tool_dump_verify_repair() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStore::open(temp_dir.path()).unwrap();
+    store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    store.remove("key1".to_owned()).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(["dump"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\t1\tset\tkey1\n").and(contains("\t2\ttombstone\tkey1\n")));
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("ok\n");
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(["stats"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("records: 2\nkeys: 0\n"));
+
+    // a torn write at the end of the log
+    let mut data = fs::OpenOptions::new().append(true).open(temp_dir.path().join(".data")).unwrap();
+    std::io::Write::write_all(&mut data, b"[\"key2\",\"val").unwrap();
+    drop(data);
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("do not parse"));
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(["repair"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("kept 2 records, dropped 12 bytes, the old log is kept as .data.corrupt\n");
+    Command::cargo_bin("kvs-tool")
+        .unwrap()
+        .args(["verify"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+}
+
+#[test]
+fn cli_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4014";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr.parse().unwrap());
+    client.request(&Request::Set(b"key1".to_vec(), b"value1".to_vec())).unwrap();
+    client.request(&Request::Get(b"key1".to_vec())).unwrap();
+    assert_eq!(client.stats().unwrap().writes, 1);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("keys: 1\n").and(contains("reads: 1\nwrites: 1\n")).and(contains("last compaction: never\n")));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("fail to wait server");
+}
+
+#[test]
+fn cli_compact() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4017";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--no-auto-compaction"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr.parse().unwrap());
+    client.request(&Request::Set(b"key1".to_vec(), b"value1".to_vec())).unwrap();
+    client.request(&Request::Set(b"key1".to_vec(), b"value2".to_vec())).unwrap();
+    assert!(client.stats().unwrap().dead_bytes > 0);
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["compact", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    let stats = client.stats().unwrap();
+    assert_eq!((stats.dead_bytes, stats.compactions), (0, 1));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("fail to wait server");
+}
+
+#[test]
+fn cli_memory_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4018";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("fail to wait server");
+}
+
+#[test]
+fn server_metrics() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4015";
+    let metrics_addr = "127.0.0.1:4016";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--metrics-addr", metrics_addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    //connections that never send anything hold up neither the clients nor the metrics
+    let _silent = std::net::TcpStream::connect(addr).unwrap();
+    let _silent_scrape = std::net::TcpStream::connect(metrics_addr).unwrap();
+    let client = KvsClient::new(addr.parse().unwrap());
+    client.request(&Request::Set(b"key1".to_vec(), b"value1".to_vec())).unwrap();
+    client.request(&Request::Get(b"key1".to_vec())).unwrap();
+    client.request(&Request::Remove(b"missing".to_vec())).unwrap();
+
+    let mut stream = std::net::TcpStream::connect(metrics_addr).unwrap();
+    std::io::Write::write_all(&mut stream, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    std::io::Read::read_to_string(&mut stream, &mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("kvs_requests_total{command=\"set\"} 1\n"));
+    assert!(response.contains("kvs_errors_total{command=\"remove\"} 1\n"));
+    assert!(response.contains("kvs_request_duration_seconds_count{command=\"get\"} 1\n"));
+    assert!(response.contains("kvs_open_connections 1\n"));
+    assert!(response.contains("kvs_keys 1\n"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("fail to wait server");
+}
+
+#[test]
+fn cli_bench() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4019";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(["--addr", addr, "--threads", "2", "--records", "100", "--duration", "1", "--workload", "d"])
+        .assert()
+        .success()
+        .stdout(contains("loaded 100 records").and(contains("read: ")).and(contains("insert: ")).and(contains("p999")));
+    let client = KvsClient::new(addr.parse().unwrap());
+    assert!(client.stats().unwrap().keys > 100);
+
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(["--addr", addr, "--records", "100", "--duration", "1", "--skip-load", "--read-ratio", "0.9", "--distribution", "zipfian"])
+        .assert()
+        .success()
+        .stdout(contains("update: ").and(contains("total: ")).and(contains("loaded").not()));
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(["--addr", addr, "--workload", "e"])
+        .assert()
+        .failure()
+        .stderr(contains("range scans"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("fail to wait server");
+}
+
+#[test]
+fn cli_cache() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4020";
+    let metrics_addr = "127.0.0.1:4021";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr, "--cache-bytes", "1048576", "--metrics-addr", metrics_addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = KvsClient::new(addr.parse().unwrap());
+    client.request(&Request::Set(b"key1".to_vec(), b"value1".to_vec())).unwrap();
+    client.request(&Request::Get(b"key1".to_vec())).unwrap();
+    client.request(&Request::Get(b"key1".to_vec())).unwrap();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("cache hits: 1\ncache misses: 1\n"));
+    let mut stream = std::net::TcpStream::connect(metrics_addr).unwrap();
+    std::io::Write::write_all(&mut stream, b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    std::io::Read::read_to_string(&mut stream, &mut response).unwrap();
+    assert!(response.contains("kvs_cache_hits_total 1\n"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("fail to wait server");
+}
@@ -0,0 +1,276 @@
+//! Conformance suite every `KvsEngine` has to pass. Each check is a generic function taking a way to
+//! open the engine on a directory, `conformance_tests!` turns them into tests for one engine.
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tempfile::TempDir;
+use ::kvs::{CompareAndSwapError, Error, KvStore, KvsEngine, MemoryKvsEngine, Options, Result, SledKvsEngine, Transaction};
+
+macro_rules! conformance_tests {
+    ($name:ident, $open:expr) => {
+        mod $name {
+            use super::*;
+
+            #[test]
+            fn set_get() -> Result<()> {
+                super::set_get($open)
+            }
+
+            #[test]
+            fn overwrite() -> Result<()> {
+                super::overwrite($open)
+            }
+
+            #[test]
+            fn get_missing() -> Result<()> {
+                super::get_missing($open)
+            }
+
+            #[test]
+            fn remove() -> Result<()> {
+                super::remove($open)
+            }
+
+            #[test]
+            fn persistence() -> Result<()> {
+                super::persistence($open)
+            }
+
+            #[test]
+            fn binary_keys_and_values() -> Result<()> {
+                super::binary_keys_and_values($open)
+            }
+
+            #[test]
+            fn large_values() -> Result<()> {
+                super::large_values($open)
+            }
+
+            #[test]
+            fn many_keys() -> Result<()> {
+                super::many_keys($open)
+            }
+
+            #[test]
+            fn conditional_writes() -> Result<()> {
+                super::conditional_writes($open)
+            }
+
+            #[test]
+            fn snapshot_isolation() -> Result<()> {
+                super::snapshot_isolation($open)
+            }
+
+            #[test]
+            fn transaction_conflict() -> Result<()> {
+                super::transaction_conflict($open)
+            }
+
+            #[test]
+            fn concurrency() -> Result<()> {
+                super::concurrency($open)
+            }
+        }
+    };
+}
+
+conformance_tests!(kvs, |dir: &Path| KvStore::open(dir));
+conformance_tests!(kvs_hashed_keys, |dir: &Path| KvStore::open_with(dir, Options { hashed_keys: true, ..Options::default() }));
+conformance_tests!(kvs_mmap, |dir: &Path| KvStore::open_with(dir, Options { mmap: true, ..Options::default() }));
+conformance_tests!(sled, |dir: &Path| SledKvsEngine::open(dir));
+//reopening hands out another handle to the same store
+conformance_tests!(memory, { let engine = MemoryKvsEngine::new(); move |_: &Path| Ok(engine.clone()) });
+
+fn set_get<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+fn overwrite<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+fn get_missing<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+fn remove<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(matches!(engine.remove("key1".to_owned()), Err(Error::KeyNotFoundError)));
+    Ok(())
+}
+
+fn persistence<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    drop(engine);
+
+    let mut engine = open(dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    engine.compact()?;
+    drop(engine);
+
+    let mut engine = open(dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+fn binary_keys_and_values<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = open(dir.path())?;
+    let key = vec![0, 159, 146, 150, 255];
+    let value = vec![255, 0, b'"', b'\n', 1];
+    engine.set_bytes(key.clone(), value.clone())?;
+    assert_eq!(engine.get_bytes(key.clone())?, Some(value.clone()));
+    engine.set_bytes(b"key1".to_vec(), value.clone())?;
+    assert!(engine.get("key1".to_owned()).is_err());
+    drop(engine);
+
+    let mut engine = open(dir.path())?;
+    assert_eq!(engine.get_bytes(key.clone())?, Some(value));
+    engine.remove_bytes(key.clone())?;
+    assert_eq!(engine.get_bytes(key)?, None);
+    Ok(())
+}
+
+fn large_values<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = open(dir.path())?;
+    let value: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+    engine.set_bytes(b"large".to_vec(), value.clone())?;
+    drop(engine);
+    let mut engine = open(dir.path())?;
+    assert_eq!(engine.get_bytes(b"large".to_vec())?, Some(value));
+    Ok(())
+}
+
+fn many_keys<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = open(dir.path())?;
+    for i in 0..2000 {
+        engine.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in (0..2000).step_by(2) {
+        engine.remove(format!("key{}", i))?;
+    }
+    drop(engine);
+    let mut engine = open(dir.path())?;
+    for i in 0..2000 {
+        let expected = if i % 2 == 0 { None } else { Some(format!("value{}", i)) };
+        assert_eq!(engine.get(format!("key{}", i))?, expected);
+    }
+    assert_eq!(engine.stats()?.keys, 1000);
+    Ok(())
+}
+
+fn conditional_writes<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = open(dir.path())?;
+    assert_eq!(engine.set_if_absent("key1".to_owned(), "value1".to_owned())?, Ok(()));
+    assert_eq!(engine.set_if_absent("key1".to_owned(), "value2".to_owned())?,
+               Err(CompareAndSwapError { current: Some("value1".to_owned()) }));
+    assert_eq!(engine.compare_and_swap("key1".to_owned(), Some("value0".to_owned()), Some("value2".to_owned()))?,
+               Err(CompareAndSwapError { current: Some("value1".to_owned()) }));
+    assert_eq!(engine.compare_and_swap("key1".to_owned(), Some("value1".to_owned()), Some("value2".to_owned()))?, Ok(()));
+    assert_eq!(engine.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.compare_and_swap("key1".to_owned(), Some("value2".to_owned()), None)?, Ok(()));
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.compare_and_swap("key1".to_owned(), Some("value2".to_owned()), None)?,
+               Err(CompareAndSwapError { current: None }));
+    assert_eq!(engine.set_if_absent("key1".to_owned(), "value1".to_owned())?, Ok(()));
+    assert_eq!(engine.increment("counter".to_owned(), 3)?, 3);
+    assert_eq!(engine.increment("counter".to_owned(), -5)?, -2);
+    assert!(matches!(engine.increment("key1".to_owned(), 1), Err(Error::InvalidNumberError)));
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("counter".to_owned())?, Some("-2".to_owned()));
+    Ok(())
+}
+
+fn snapshot_isolation<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let mut snapshot = engine.snapshot()?;
+    engine.set("key1".to_owned(), "value2".to_owned())?;
+    engine.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+fn transaction_conflict<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = open(dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let mut txn = Transaction::new();
+    txn.get(&mut engine, b"key1".to_vec())?;
+    txn.set(b"key2".to_vec(), b"value2".to_vec());
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    assert!(matches!(engine.commit(txn), Err(Error::TransactionConflictError)));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    //a value written back to what was read is still a change
+    let mut txn = Transaction::new();
+    txn.get(&mut engine, b"key1".to_vec())?;
+    txn.set(b"key2".to_vec(), b"value2".to_vec());
+    engine.set("key1".to_owned(), "value4".to_owned())?;
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    assert!(matches!(engine.commit(txn), Err(Error::TransactionConflictError)));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+
+    //so is a key read as absent that was set and removed again
+    let mut txn = Transaction::new();
+    assert_eq!(txn.get(&mut engine, b"key3".to_vec())?, None);
+    txn.set(b"key2".to_vec(), b"value2".to_vec());
+    engine.set("key3".to_owned(), "value5".to_owned())?;
+    engine.remove("key3".to_owned())?;
+    assert!(matches!(engine.commit(txn), Err(Error::TransactionConflictError)));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+fn concurrency<E: KvsEngine + Send + 'static>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = Arc::new(Mutex::new(open(dir.path())?));
+    let handles: Vec<_> = (0..8).map(|thread| {
+        let engine = engine.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 0..100 {
+                engine.lock().unwrap().set(format!("key{}-{}", thread, i), format!("value{}", i))?;
+                engine.lock().unwrap().increment("counter".to_owned(), 1)?;
+            }
+            Ok(())
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let mut engine = engine.lock().unwrap();
+    assert_eq!(engine.get("counter".to_owned())?, Some("800".to_owned()));
+    assert_eq!(engine.get("key7-99".to_owned())?, Some("value99".to_owned()));
+    assert_eq!(engine.stats()?.keys, 801);
+    Ok(())
+}
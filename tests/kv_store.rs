@@ -1,4 +1,4 @@
-use Kvs::{KvStore, KvsEngine, Result};
+use kvs::{export, import, migrate, Error, ExportFormat, KvStore, KvsEngine, Options, Result, SledKvsEngine};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -123,4 +123,89 @@ fn compaction() -> Result<()> {
     }
 
     panic!("No compaction detected");
-}
\ No newline at end of file
+}
+
+// Export and import should round trip text and binary pairs in both formats
+#[test]
+fn export_import_round_trip() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_bytes(vec![0xff, 0], vec![0xfe])?;
+    store.set("removed".to_owned(), "value".to_owned())?;
+    store.remove("removed".to_owned())?;
+
+    for format in [ExportFormat::Json, ExportFormat::Binary] {
+        let mut buffer = vec![];
+        assert_eq!(export(&mut store, format, &mut buffer)?, 2);
+        let target_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut target = SledKvsEngine::open(target_dir.path())?;
+        assert_eq!(import(&mut target, format, buffer.as_slice())?, 2);
+        assert_eq!(target.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(target.get_bytes(vec![0xff, 0])?, Some(vec![0xfe]));
+        assert_eq!(target.get("removed".to_owned())?, None);
+    }
+
+    let mut json = vec![];
+    export(&mut store, ExportFormat::Json, &mut json)?;
+    assert_eq!(String::from_utf8(json).unwrap(), "{\"key\":\"key1\",\"value\":\"value1\"}\n{\"key\":{\"b64\":\"/wA=\"},\"value\":{\"b64\":\"/g==\"}}\n");
+
+    // binary values stay close to their size in both formats
+    let value: Vec<u8> = (0..4096).map(|i| (i * 7 % 256) as u8).collect();
+    store.set_bytes(vec![0xfd], value.clone())?;
+    for format in [ExportFormat::Json, ExportFormat::Binary] {
+        let mut buffer = vec![];
+        export(&mut store, format, &mut buffer)?;
+        assert!(buffer.len() < value.len() * 4 / 3 + 128);
+        let target_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut target = KvStore::open(target_dir.path())?;
+        import(&mut target, format, buffer.as_slice())?;
+        assert_eq!(target.get_bytes(vec![0xfd])?, Some(value.clone()));
+    }
+
+    // a truncated binary stream is rejected
+    let mut binary = vec![];
+    export(&mut store, ExportFormat::Binary, &mut binary)?;
+    binary.pop();
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut target = KvStore::open(target_dir.path())?;
+    assert!(matches!(import(&mut target, ExportFormat::Binary, binary.as_slice()), Err(Error::InvalidEncodingError)));
+
+    // so is a length the stream does not hold, without allocating it
+    let mut binary = b"KVSX\x01".to_vec();
+    binary.extend_from_slice(&u32::MAX.to_le_bytes());
+    binary.extend_from_slice(b"key");
+    assert!(matches!(import(&mut target, ExportFormat::Binary, binary.as_slice()), Err(Error::InvalidEncodingError)));
+    Ok(())
+}
+
+// Migration should copy every live pair between engines
+#[test]
+fn migrate_between_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut sled = SledKvsEngine::open(sled_dir.path())?;
+    assert_eq!(migrate(&mut store, &mut sled)?, 100);
+    let back_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut back = KvStore::open(back_dir.path())?;
+    assert_eq!(migrate(&mut sled, &mut back)?, 100);
+    for i in 0..100 {
+        assert_eq!(back.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+
+    // a sled source is read in place, not through a snapshot and its size limit
+    drop(sled);
+    let mut sled = SledKvsEngine::open_with(sled_dir.path(), Options { snapshot_bytes: Some(16), ..Options::default() })?;
+    assert!(sled.snapshot().is_err());
+    let mut buffer = vec![];
+    assert_eq!(export(&mut sled, ExportFormat::Binary, &mut buffer)?, 100);
+    let again_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut again = KvStore::open(again_dir.path())?;
+    assert_eq!(migrate(&mut sled, &mut again)?, 100);
+    assert_eq!(again.get("key99".to_owned())?, Some("value99".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,78 @@
+//! Property tests applying random operation sequences to `KvStore` and to a `HashMap` model.
+//! proptest shrinks a failing sequence down to a minimal one before reporting it.
+use proptest::prelude::*;
+use std::collections::HashMap;
+use std::path::Path;
+use tempfile::TempDir;
+use kvs::{CompactionPolicy, Error, KvStore, KvsEngine, Options};
+
+///a few keys so operations keep hitting the same ones
+const KEYS: u8 = 8;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Set(u8, Vec<u8>),
+    Remove(u8),
+    Reopen,
+    Compact,
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (0..KEYS, prop::collection::vec(any::<u8>(), 0..64)).prop_map(|(key, value)| Op::Set(key, value)),
+        2 => (0..KEYS).prop_map(Op::Remove),
+        1 => Just(Op::Reopen),
+        1 => Just(Op::Compact),
+    ]
+}
+
+fn key(key: u8) -> Vec<u8> {
+    format!("key{}", key).into_bytes()
+}
+
+///a low threshold so that automatic compaction runs in the middle of the sequences as well
+fn open(dir: &Path) -> kvs::Result<KvStore> {
+    let compaction = CompactionPolicy { dead_bytes: 256, ..CompactionPolicy::default() };
+    KvStore::open_with(dir, Options { compaction, ..Options::default() })
+}
+
+fn check(store: &mut KvStore, model: &HashMap<Vec<u8>, Vec<u8>>) -> Result<(), TestCaseError> {
+    for k in 0..KEYS {
+        prop_assert_eq!(store.get_bytes(key(k)).unwrap(), model.get(&key(k)).cloned(), "key{}", k);
+    }
+    let stats = store.stats().unwrap();
+    prop_assert_eq!(stats.keys as usize, model.len());
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn matches_model(ops in prop::collection::vec(op(), 1..100)) {
+        let dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = open(dir.path()).unwrap();
+        let mut model = HashMap::new();
+        for op in ops {
+            match op {
+                Op::Set(k, value) => {
+                    store.set_bytes(key(k), value.clone()).unwrap();
+                    model.insert(key(k), value);
+                }
+                Op::Remove(k) => match (store.remove_bytes(key(k)), model.remove(&key(k))) {
+                    (Ok(()), Some(_)) | (Err(Error::KeyNotFoundError), None) => {}
+                    (result, expected) => prop_assert!(false, "remove key{} gave {:?}, the model held {:?}", k, result, expected),
+                },
+                Op::Reopen => {
+                    drop(store);
+                    store = open(dir.path()).unwrap();
+                }
+                Op::Compact => store.compact().unwrap(),
+            }
+            check(&mut store, &model)?;
+        }
+        drop(store);
+        let mut store = open(dir.path()).unwrap();
+        check(&mut store, &model)?;
+    }
+}